@@ -0,0 +1,504 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+
+use super::machine::*;
+
+#[derive(Debug)]
+pub struct AsmError {
+	line: usize,
+	message: String,
+}
+
+impl Display for AsmError {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		write!(f, "line {}: {}", self.line, self.message)
+	}
+}
+
+impl Error for AsmError {}
+
+pub struct Section {
+	pub addr: u32,
+	pub instrs: Vec<Box<dyn Instruction>>,
+}
+
+impl Section {
+	fn new(addr: u32) -> Self {
+		Self { addr, instrs: Vec::new() }
+	}
+
+	pub fn machine_code(&self) -> Vec<u32> {
+		self.instrs.iter().map(|instr| instr.to_machine_code()).collect()
+	}
+}
+
+pub struct Program {
+	pub text: Section,
+	pub ktext: Section,
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+enum SectionKind {
+	Text,
+	KText,
+}
+
+struct Line<'a> {
+	line: usize,
+	section: SectionKind,
+	addr: u32,
+	mnemonic: &'a str,
+	operands: Vec<&'a str>,
+}
+
+const REG_NAMES: [&str; GRF_SIZE] = [
+	"zero", "at", "v0", "v1", "a0", "a1", "a2", "a3",
+	"t0", "t1", "t2", "t3", "t4", "t5", "t6", "t7",
+	"s0", "s1", "s2", "s3", "s4", "s5", "s6", "s7",
+	"t8", "t9", "k0", "k1", "gp", "sp", "fp", "ra",
+];
+
+fn parse_int(s: &str) -> Option<i64> {
+	let (neg, s) = if let Some(s) = s.strip_prefix('-') { (true, s) } else { (false, s) };
+	let value = if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+		i64::from_str_radix(hex, 16).ok()?
+	} else {
+		s.parse::<i64>().ok()?
+	};
+	Some(if neg { -value } else { value })
+}
+
+fn parse_reg(s: &str) -> Option<u8> {
+	let name = s.strip_prefix('$')?;
+	name.parse::<u8>().ok().filter(|addr| (*addr as usize) < GRF_SIZE)
+		.or_else(|| REG_NAMES.iter().position(|x| *x == name).map(|addr| addr as u8))
+}
+
+fn is_label(s: &str) -> bool {
+	let mut chars = s.chars();
+	matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_' || c == '.')
+		&& chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+impl<'a> Line<'a> {
+	fn error(&self, message: String) -> AsmError {
+		AsmError { line: self.line, message }
+	}
+
+	fn check_operand_count(&self, count: usize) -> Result<(), AsmError> {
+		if self.operands.len() == count {
+			Ok(())
+		} else {
+			Err(self.error(format!("{} expects {} operands, got {}", self.mnemonic, count, self.operands.len())))
+		}
+	}
+
+	fn reg(&self, id: usize) -> Result<u8, AsmError> {
+		let s = self.operands[id];
+		parse_reg(s).ok_or_else(|| self.error(format!("invalid register \"{}\"", s)))
+	}
+
+	fn int(&self, id: usize, min: i64, max: i64) -> Result<i64, AsmError> {
+		let s = self.operands[id];
+		parse_int(s)
+			.filter(|x| (min..=max).contains(x))
+			.ok_or_else(|| self.error(format!("invalid immediate \"{}\", expected {} to {}", s, min, max)))
+	}
+
+	fn imm_signed(&self, id: usize) -> Result<i16, AsmError> {
+		self.int(id, i16::MIN as i64, u16::MAX as i64).map(|x| x as i16)
+	}
+
+	fn imm_unsigned(&self, id: usize) -> Result<u16, AsmError> {
+		self.int(id, 0, u16::MAX as i64).map(|x| x as u16)
+	}
+
+	fn shamt(&self, id: usize) -> Result<u8, AsmError> {
+		self.int(id, 0, 31).map(|x| x as u8)
+	}
+
+	fn mem(&self, id: usize) -> Result<(u8, i16), AsmError> {
+		let s = self.operands[id];
+		let err = || self.error(format!("invalid memory operand \"{}\"", s));
+		let (offset, base) = s.strip_suffix(')').and_then(|s| s.split_once('(')).ok_or_else(err)?;
+		let base = parse_reg(base.trim()).ok_or_else(err)?;
+		let offset = match offset.trim() {
+			"" => 0,
+			offset => parse_int(offset)
+				.filter(|x| (i16::MIN as i64..=u16::MAX as i64).contains(x))
+				.ok_or_else(err)? as i16,
+		};
+		Ok((base, offset))
+	}
+
+	fn label(&self, id: usize, labels: &HashMap<&str, u32>) -> Result<u32, AsmError> {
+		let s = self.operands[id];
+		labels.get(s).copied().ok_or_else(|| self.error(format!("undefined label \"{}\"", s)))
+	}
+
+	fn branch_offset(&self, id: usize, labels: &HashMap<&str, u32>) -> Result<i16, AsmError> {
+		if parse_int(self.operands[id]).is_some() {
+			return self.int(id, i16::MIN as i64, i16::MAX as i64).map(|x| x as i16);
+		}
+		let target = self.label(id, labels)?;
+		let offset = (target as i64 - self.addr as i64 - WORD_SIZE as i64) / WORD_SIZE as i64;
+		if offset < i16::MIN as i64 || offset > i16::MAX as i64 {
+			return Err(self.error(format!("branch target \"{}\" is out of range", self.operands[id])));
+		}
+		Ok(offset as i16)
+	}
+
+	fn jump_addr(&self, id: usize, labels: &HashMap<&str, u32>) -> Result<u32, AsmError> {
+		if parse_int(self.operands[id]).is_some() {
+			return self.int(id, 0, (1 << 26) - 1).map(|x| x as u32);
+		}
+		let target = self.label(id, labels)?;
+		if (target ^ self.addr) >> 28 != 0 {
+			return Err(self.error(format!("jump target \"{}\" is out of range", self.operands[id])));
+		}
+		Ok((target / WORD_SIZE as u32) & ((1 << 26) - 1))
+	}
+
+	fn to_instruction(&self, labels: &HashMap<&str, u32>) -> Result<Box<dyn Instruction>, AsmError> {
+		let instr: Box<dyn Instruction> = match self.mnemonic {
+			"nop" => {
+				self.check_operand_count(0)?;
+				Box::new(NopInstr)
+			}
+			"add" | "addu" | "sub" | "subu" | "and" | "or" | "xor" | "nor" | "slt" | "sltu" | "movz" | "movn" | "mul" => {
+				self.check_operand_count(3)?;
+				let (rd, rs, rt) = (self.reg(0)?, self.reg(1)?, self.reg(2)?);
+				match self.mnemonic {
+					"add" => Box::new(AddInstr { rs, rt, rd }),
+					"addu" => Box::new(AdduInstr { rs, rt, rd }),
+					"sub" => Box::new(SubInstr { rs, rt, rd }),
+					"subu" => Box::new(SubuInstr { rs, rt, rd }),
+					"and" => Box::new(AndInstr { rs, rt, rd }),
+					"or" => Box::new(OrInstr { rs, rt, rd }),
+					"xor" => Box::new(XorInstr { rs, rt, rd }),
+					"nor" => Box::new(NorInstr { rs, rt, rd }),
+					"slt" => Box::new(SltInstr { rs, rt, rd }),
+					"sltu" => Box::new(SltuInstr { rs, rt, rd }),
+					"movz" => Box::new(MovzInstr { rs, rt, rd }),
+					"movn" => Box::new(MovnInstr { rs, rt, rd }),
+					_ => Box::new(MulInstr { rs, rt, rd }),
+				}
+			}
+			"sllv" | "srlv" | "srav" => {
+				self.check_operand_count(3)?;
+				let (rd, rt, rs) = (self.reg(0)?, self.reg(1)?, self.reg(2)?);
+				match self.mnemonic {
+					"sllv" => Box::new(SllvInstr { rs, rt, rd }),
+					"srlv" => Box::new(SrlvInstr { rs, rt, rd }),
+					_ => Box::new(SravInstr { rs, rt, rd }),
+				}
+			}
+			"sll" | "srl" | "sra" => {
+				self.check_operand_count(3)?;
+				let (rd, rt, sa) = (self.reg(0)?, self.reg(1)?, self.shamt(2)?);
+				match self.mnemonic {
+					"sll" => Box::new(SllInstr { rt, rd, sa }),
+					"srl" => Box::new(SrlInstr { rt, rd, sa }),
+					_ => Box::new(SraInstr { rt, rd, sa }),
+				}
+			}
+			"addi" | "addiu" | "slti" | "sltiu" => {
+				self.check_operand_count(3)?;
+				let (rt, rs, imm) = (self.reg(0)?, self.reg(1)?, self.imm_signed(2)?);
+				match self.mnemonic {
+					"addi" => Box::new(AddiInstr { rs, rt, imm }),
+					"addiu" => Box::new(AddiuInstr { rs, rt, imm }),
+					"slti" => Box::new(SltiInstr { rs, rt, imm }),
+					_ => Box::new(SltiuInstr { rs, rt, imm }),
+				}
+			}
+			"andi" | "ori" | "xori" => {
+				self.check_operand_count(3)?;
+				let (rt, rs, imm) = (self.reg(0)?, self.reg(1)?, self.imm_unsigned(2)?);
+				match self.mnemonic {
+					"andi" => Box::new(AndiInstr { rs, rt, imm }),
+					"ori" => Box::new(OriInstr { rs, rt, imm }),
+					_ => Box::new(XoriInstr { rs, rt, imm }),
+				}
+			}
+			"lui" => {
+				self.check_operand_count(2)?;
+				Box::new(LuiInstr { rt: self.reg(0)?, imm: self.imm_unsigned(1)? })
+			}
+			"lb" | "lbu" | "lh" | "lhu" | "lw" | "lwl" | "lwr" | "ll" | "sb" | "sh" | "sw" | "swl" | "swr" | "sc" => {
+				self.check_operand_count(2)?;
+				let rt = self.reg(0)?;
+				let (base, offset) = self.mem(1)?;
+				match self.mnemonic {
+					"lb" => Box::new(LbInstr { base, rt, offset }),
+					"lbu" => Box::new(LbuInstr { base, rt, offset }),
+					"lh" => Box::new(LhInstr { base, rt, offset }),
+					"lhu" => Box::new(LhuInstr { base, rt, offset }),
+					"lw" => Box::new(LwInstr { base, rt, offset }),
+					"lwl" => Box::new(LwlInstr { base, rt, offset }),
+					"lwr" => Box::new(LwrInstr { base, rt, offset }),
+					"ll" => Box::new(LlInstr { base, rt, offset }),
+					"sb" => Box::new(SbInstr { base, rt, offset }),
+					"sh" => Box::new(ShInstr { base, rt, offset }),
+					"sw" => Box::new(SwInstr { base, rt, offset }),
+					"swl" => Box::new(SwlInstr { base, rt, offset }),
+					"swr" => Box::new(SwrInstr { base, rt, offset }),
+					_ => Box::new(ScInstr { base, rt, offset }),
+				}
+			}
+			"beq" | "bne" | "beql" | "bnel" => {
+				self.check_operand_count(3)?;
+				let (rs, rt, offset) = (self.reg(0)?, self.reg(1)?, self.branch_offset(2, labels)?);
+				match self.mnemonic {
+					"beq" => Box::new(BeqInstr { rs, rt, offset }),
+					"bne" => Box::new(BneInstr { rs, rt, offset }),
+					"beql" => Box::new(BeqlInstr { rs, rt, offset }),
+					_ => Box::new(BnelInstr { rs, rt, offset }),
+				}
+			}
+			"blez" | "bltz" | "bgez" | "bgtz" | "bltzal" | "bgezal" => {
+				self.check_operand_count(2)?;
+				let (rs, offset) = (self.reg(0)?, self.branch_offset(1, labels)?);
+				match self.mnemonic {
+					"blez" => Box::new(BlezInstr { rs, offset }),
+					"bltz" => Box::new(BltzInstr { rs, offset }),
+					"bgez" => Box::new(BgezInstr { rs, offset }),
+					"bgtz" => Box::new(BgtzInstr { rs, offset }),
+					"bltzal" => Box::new(BltzalInstr { rs, offset }),
+					_ => Box::new(BgezalInstr { rs, offset }),
+				}
+			}
+			"j" | "jal" => {
+				self.check_operand_count(1)?;
+				let addr = self.jump_addr(0, labels)?;
+				match self.mnemonic {
+					"j" => Box::new(JInstr { addr }),
+					_ => Box::new(JalInstr { addr }),
+				}
+			}
+			"jr" => {
+				self.check_operand_count(1)?;
+				Box::new(JrInstr { rs: self.reg(0)? })
+			}
+			"jalr" => {
+				let (rd, rs) = match self.operands.len() {
+					1 => (31, self.reg(0)?),
+					_ => {
+						self.check_operand_count(2)?;
+						(self.reg(0)?, self.reg(1)?)
+					}
+				};
+				Box::new(JalrInstr { rs, rd })
+			}
+			"clz" | "clo" => {
+				self.check_operand_count(2)?;
+				let (rd, rs) = (self.reg(0)?, self.reg(1)?);
+				match self.mnemonic {
+					"clz" => Box::new(ClzInstr { rs, rd }),
+					_ => Box::new(CloInstr { rs, rd }),
+				}
+			}
+			"mult" | "multu" | "div" | "divu" | "madd" | "maddu" | "msub" | "msubu" => {
+				self.check_operand_count(2)?;
+				let (rs, rt) = (self.reg(0)?, self.reg(1)?);
+				match self.mnemonic {
+					"mult" => Box::new(MultInstr { rs, rt }),
+					"multu" => Box::new(MultuInstr { rs, rt }),
+					"div" => Box::new(DivInstr { rs, rt }),
+					"divu" => Box::new(DivuInstr { rs, rt }),
+					"madd" => Box::new(MaddInstr { rs, rt }),
+					"maddu" => Box::new(MadduInstr { rs, rt }),
+					"msub" => Box::new(MsubInstr { rs, rt }),
+					_ => Box::new(MsubuInstr { rs, rt }),
+				}
+			}
+			"teq" | "tne" | "tge" | "tgeu" | "tlt" | "tltu" => {
+				self.check_operand_count(2)?;
+				let (rs, rt) = (self.reg(0)?, self.reg(1)?);
+				match self.mnemonic {
+					"teq" => Box::new(TeqInstr { rs, rt }),
+					"tne" => Box::new(TneInstr { rs, rt }),
+					"tge" => Box::new(TgeInstr { rs, rt }),
+					"tgeu" => Box::new(TgeuInstr { rs, rt }),
+					"tlt" => Box::new(TltInstr { rs, rt }),
+					_ => Box::new(TltuInstr { rs, rt }),
+				}
+			}
+			"teqi" | "tnei" | "tgei" | "tgeiu" | "tlti" | "tltiu" => {
+				self.check_operand_count(2)?;
+				let (rs, imm) = (self.reg(0)?, self.imm_signed(1)?);
+				match self.mnemonic {
+					"teqi" => Box::new(TeqiInstr { rs, imm }),
+					"tnei" => Box::new(TneiInstr { rs, imm }),
+					"tgei" => Box::new(TgeiInstr { rs, imm }),
+					"tgeiu" => Box::new(TgeiuInstr { rs, imm }),
+					"tlti" => Box::new(TltiInstr { rs, imm }),
+					_ => Box::new(TltiuInstr { rs, imm }),
+				}
+			}
+			"mflo" | "mfhi" => {
+				self.check_operand_count(1)?;
+				let rd = self.reg(0)?;
+				match self.mnemonic {
+					"mflo" => Box::new(MfloInstr { rd }),
+					_ => Box::new(MfhiInstr { rd }),
+				}
+			}
+			"mtlo" | "mthi" => {
+				self.check_operand_count(1)?;
+				let rs = self.reg(0)?;
+				match self.mnemonic {
+					"mtlo" => Box::new(MtloInstr { rs }),
+					_ => Box::new(MthiInstr { rs }),
+				}
+			}
+			"mfc0" | "mtc0" => {
+				self.check_operand_count(2)?;
+				let (rt, rd) = (self.reg(0)?, self.reg(1)?);
+				match self.mnemonic {
+					"mfc0" => Box::new(Mfc0Instr { rt, rd }),
+					_ => Box::new(Mtc0Instr { rt, rd }),
+				}
+			}
+			"syscall" | "break" => {
+				let code = match self.operands.len() {
+					0 => 0,
+					_ => {
+						self.check_operand_count(1)?;
+						self.int(0, 0, (1 << 20) - 1)? as u32
+					}
+				};
+				match self.mnemonic {
+					"syscall" => Box::new(SyscallInstr { code }),
+					_ => Box::new(BreakInstr { code }),
+				}
+			}
+			"eret" => {
+				self.check_operand_count(0)?;
+				Box::new(EretInstr)
+			}
+			".word" => {
+				self.check_operand_count(1)?;
+				let code = self.int(0, 0, u32::MAX as i64)? as u32;
+				decode_instruction(code).unwrap_or_else(|_| Box::new(ReservedInstr { code }))
+			}
+			_ => return Err(self.error(format!("unknown instruction \"{}\"", self.mnemonic))),
+		};
+		Ok(instr)
+	}
+}
+
+pub fn assemble(src: &str) -> Result<Program, AsmError> {
+	let mut section = SectionKind::Text;
+	let mut start_addr = [TEXT_START_ADDR, HANDLER_ADDR];
+	let mut next_addr = start_addr;
+	let mut labels = HashMap::new();
+	let mut lines = Vec::new();
+	for (i, line) in src.lines().enumerate() {
+		let err = |message| AsmError { line: i + 1, message };
+		let mut line = line.split('#').next().unwrap().trim();
+		while let Some((label, rest)) = line.split_once(':') {
+			let label = label.trim();
+			if !is_label(label) {
+				return Err(err(format!("invalid label \"{}\"", label)));
+			}
+			if labels.insert(label, next_addr[section as usize]).is_some() {
+				return Err(err(format!("duplicate label \"{}\"", label)));
+			}
+			line = rest.trim();
+		}
+		if line.is_empty() { continue; }
+		let mut tokens = line
+			.split(|c: char| c == ',' || c.is_whitespace())
+			.filter(|s| !s.is_empty());
+		let mnemonic = tokens.next().unwrap();
+		let operands = tokens.collect::<Vec<_>>();
+		match mnemonic {
+			".text" | ".ktext" => {
+				section = if mnemonic == ".text" { SectionKind::Text } else { SectionKind::KText };
+				match operands[..] {
+					[] => (),
+					[addr] => {
+						let addr = parse_int(addr)
+							.filter(|x| (0..=u32::MAX as i64).contains(x) && x % WORD_SIZE as i64 == 0)
+							.ok_or_else(|| err(format!("invalid section address \"{}\"", addr)))? as u32;
+						if next_addr[section as usize] != start_addr[section as usize] {
+							return Err(err(format!("cannot move {} after instructions were emitted", mnemonic)));
+						}
+						start_addr[section as usize] = addr;
+						next_addr[section as usize] = addr;
+					}
+					_ => return Err(err(format!("{} expects at most 1 operand", mnemonic))),
+				}
+			}
+			_ if mnemonic.starts_with('.') && mnemonic != ".word" => {
+				return Err(err(format!("unsupported directive \"{}\"", mnemonic)));
+			}
+			_ => {
+				lines.push(Line { line: i + 1, section, addr: next_addr[section as usize], mnemonic, operands });
+				next_addr[section as usize] += WORD_SIZE as u32;
+			}
+		}
+	}
+	let mut program = Program {
+		text: Section::new(start_addr[SectionKind::Text as usize]),
+		ktext: Section::new(start_addr[SectionKind::KText as usize]),
+	};
+	for line in lines {
+		let instr = line.to_instruction(&labels)?;
+		match line.section {
+			SectionKind::Text => program.text.instrs.push(instr),
+			SectionKind::KText => program.ktext.instrs.push(instr),
+		}
+	}
+	Ok(program)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn assemble_display_syntax() {
+		let src = "addu $1, $2, $3\nlw $4, -8($5)\nsllv $6, $7, $8\nori $9, $10, 65535\njalr $11, $12\nlui $13, 0x8000\nmfc0 $14, $12\nmtc0 $15, $14\neret";
+		let program = assemble(src).unwrap();
+		let printed = program.text.instrs.iter().map(|instr| instr.to_string()).collect::<Vec<_>>();
+		assert_eq!(printed.join("\n"), src.replace("0x8000", "32768"));
+	}
+
+	#[test]
+	fn assemble_labels() {
+		let src = "
+			.text
+			start: addiu $1, $0, 3 # counter
+			loop:
+			addiu $1, $1, -1
+			bne $1, $zero, loop
+			nop
+			beq $0, $0, 1
+			j start
+			.ktext
+			handler:
+			jal handler
+			sw $1 4($0)
+		";
+		let program = assemble(src).unwrap();
+		assert_eq!(program.text.addr, TEXT_START_ADDR);
+		assert_eq!(program.text.machine_code(), vec![
+			0x24010003, 0x2421ffff, 0x1420fffe, 0x00000000, 0x10000001, 0x08000c00,
+		]);
+		assert_eq!(program.ktext.addr, HANDLER_ADDR);
+		assert_eq!(program.ktext.machine_code(), vec![0x0c001060, 0xac010004]);
+	}
+
+	#[test]
+	fn assemble_errors() {
+		assert!(assemble("addu $1, $2").is_err());
+		assert!(assemble("addu $1, $2, $32").is_err());
+		assert!(assemble("andi $1, $2, -1").is_err());
+		assert!(assemble("beq $1, $2, nowhere").is_err());
+		assert!(assemble("foo $1").is_err());
+		assert!(assemble("a: nop\na: nop").is_err());
+	}
+}
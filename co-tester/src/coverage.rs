@@ -0,0 +1,246 @@
+use std::collections::BTreeMap;
+use std::fmt::{self, Display, Formatter};
+use strum::{AsStaticRef, IntoEnumIterator};
+use super::gen::InstructionType;
+use super::log::GrfLogEntry;
+use super::machine::GRF_SIZE;
+
+const PRODUCER_NAMES: [&str; 4] = ["alu", "load", "mfhi/mflo", "link"];
+const CONSUMER_NAMES: [&str; 4] = ["rs/rt", "store data", "branch compare", "jr target"];
+const DISTANCE_NAMES: [&str; 3] = ["1", "2", "3+"];
+
+// Dependencies counted by producer, consumer and distance.
+pub type HazardCounts = [[[u64; 3]; 4]; 4];
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Producer {
+	Alu,
+	Load,
+	HiLo,
+	Link,
+}
+
+impl Producer {
+	pub const ALL: [Self; 4] = [Self::Alu, Self::Load, Self::HiLo, Self::Link];
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Consumer {
+	Operand,
+	StoreData,
+	BranchCompare,
+	JumpTarget,
+}
+
+impl Consumer {
+	pub const ALL: [Self; 4] = [Self::Operand, Self::StoreData, Self::BranchCompare, Self::JumpTarget];
+
+	fn reads_of(instr: InstructionType, code: u32) -> Vec<(u8, Self)> {
+		use InstructionType::*;
+		let rs = (code >> 21 & 0x1f) as u8;
+		let rt = (code >> 16 & 0x1f) as u8;
+		match instr {
+			Nop | Lui | J | Jal | Mfhi | Mflo | Syscall | Break | Reserved => vec![],
+			Beq | Bne | Beql | Bnel => vec![(rs, Self::BranchCompare), (rt, Self::BranchCompare)],
+			Blez | Bgtz | Bltz | Bgez | Bltzal | Bgezal => vec![(rs, Self::BranchCompare)],
+			Jr | Jalr => vec![(rs, Self::JumpTarget)],
+			Sb | Sh | Sw | Swl | Swr | Sc => vec![(rs, Self::Operand), (rt, Self::StoreData)],
+			Sll | Srl | Sra => vec![(rt, Self::Operand)],
+			Clz | Clo | Mthi | Mtlo => vec![(rs, Self::Operand)],
+			Addi | Addiu | Slti | Sltiu | Andi | Ori | Xori | Lb | Lbu | Lh | Lhu | Lw | Ll |
+				Teqi | Tnei | Tgei | Tgeiu | Tlti | Tltiu => vec![(rs, Self::Operand)],
+			_ => vec![(rs, Self::Operand), (rt, Self::Operand)],
+		}
+	}
+}
+
+fn instr_name(instr: InstructionType) -> &'static str {
+	if instr == InstructionType::Reserved { ".word" } else { instr.as_static() }
+}
+
+fn exc_code_name(exc_code: u8) -> &'static str {
+	match exc_code {
+		0 => "Int",
+		4 => "AdEL",
+		5 => "AdES",
+		8 => "Sys",
+		9 => "Bp",
+		10 => "RI",
+		12 => "Ov",
+		13 => "Tr",
+		_ => "?",
+	}
+}
+
+fn irq_source_name(hw_int: usize) -> &'static str {
+	match hw_int {
+		0 => "timer 0",
+		1 => "timer 1",
+		2 => "external",
+		_ => "?",
+	}
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Coverage {
+	instr_counts: BTreeMap<InstructionType, u64>,
+	distances: HazardCounts,
+	branch_outcomes: BTreeMap<InstructionType, [u64; 2]>,
+	delay_slots: BTreeMap<InstructionType, u64>,
+	annulled_slots: u64,
+	exceptions: BTreeMap<u8, u64>,
+	irq_sources: [u64; 6],
+	step: u64,
+	last_writes: [Option<(Producer, u64)>; GRF_SIZE],
+}
+
+impl Coverage {
+	// Writes are tracked everywhere, as the handler also feeds the pipeline, but only the test program is counted.
+	pub fn record(&mut self, instr: Option<InstructionType>, code: u32, is_in_text: bool, is_in_delay_slot: bool,
+		taken: Option<bool>, writes: &[GrfLogEntry]) {
+		self.step += 1;
+		if let (Some(instr), true) = (instr, is_in_text) {
+			*self.instr_counts.entry(instr).or_default() += 1;
+			for (reg, consumer) in Consumer::reads_of(instr, code) {
+				if let Some((producer, step)) = self.last_writes[reg as usize].filter(|_| reg != 0) {
+					let distance = std::cmp::min(self.step - step, 3) as usize - 1;
+					self.distances[producer as usize][consumer as usize][distance] += 1;
+				}
+			}
+			if let Some(taken) = taken {
+				self.branch_outcomes.entry(instr).or_default()[if taken { 0 } else { 1 }] += 1;
+			}
+			if is_in_delay_slot {
+				*self.delay_slots.entry(instr).or_default() += 1;
+			}
+		}
+		// Only mfc0 writes a register without being a generated type.
+		let producer = instr.and_then(|x| x.producer()).unwrap_or(Producer::Alu);
+		for entry in writes {
+			self.last_writes[entry.addr() as usize] = Some((producer, self.step));
+		}
+	}
+
+	pub fn hazards(&self) -> &HazardCounts {
+		&self.distances
+	}
+
+	// The registers written so far, with the distance an instruction executed next would read them at.
+	pub fn pending_reads(&self) -> Vec<(u8, Producer, usize)> {
+		self.last_writes.iter().enumerate().skip(1)
+			.filter_map(|(reg, x)| x.map(|(producer, step)| {
+				(reg as u8, producer, std::cmp::min(self.step + 1 - step, 3) as usize - 1)
+			}))
+			.collect()
+	}
+
+	pub fn record_annulled(&mut self) {
+		self.step += 1;
+		self.annulled_slots += 1;
+	}
+
+	pub fn record_exception(&mut self, exc_code: u8, irqs: u32) {
+		*self.exceptions.entry(exc_code).or_default() += 1;
+		if exc_code == 0 {
+			for (i, count) in self.irq_sources.iter_mut().enumerate() {
+				if irqs & 1 << i != 0 {
+					*count += 1;
+				}
+			}
+		}
+	}
+
+	pub fn merge(&mut self, other: &Coverage) {
+		for (instr, count) in &other.instr_counts {
+			*self.instr_counts.entry(*instr).or_default() += count;
+		}
+		for (p, other_p) in self.distances.iter_mut().zip(&other.distances) {
+			for (c, other_c) in p.iter_mut().zip(other_p) {
+				for (d, other_d) in c.iter_mut().zip(other_c) {
+					*d += other_d;
+				}
+			}
+		}
+		for (instr, outcomes) in &other.branch_outcomes {
+			let entry = self.branch_outcomes.entry(*instr).or_default();
+			entry[0] += outcomes[0];
+			entry[1] += outcomes[1];
+		}
+		for (instr, count) in &other.delay_slots {
+			*self.delay_slots.entry(*instr).or_default() += count;
+		}
+		self.annulled_slots += other.annulled_slots;
+		for (exc_code, count) in &other.exceptions {
+			*self.exceptions.entry(*exc_code).or_default() += count;
+		}
+		for (count, other_count) in self.irq_sources.iter_mut().zip(&other.irq_sources) {
+			*count += other_count;
+		}
+	}
+}
+
+impl Display for Coverage {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		writeln!(f, "{:<16}{:>12}", "instruction", "executed")?;
+		for instr in InstructionType::iter() {
+			writeln!(f, "{:<16}{:>12}", instr_name(instr), self.instr_counts.get(&instr).copied().unwrap_or_default())?;
+		}
+
+		writeln!(f, "\n{:<12}{:<16}{:>12}{:>12}{:>12}", "producer", "consumer", DISTANCE_NAMES[0], DISTANCE_NAMES[1], DISTANCE_NAMES[2])?;
+		for (p, producer) in PRODUCER_NAMES.iter().enumerate() {
+			for (c, consumer) in CONSUMER_NAMES.iter().enumerate() {
+				let counts = self.distances[p][c];
+				writeln!(f, "{:<12}{:<16}{:>12}{:>12}{:>12}", producer, consumer, counts[0], counts[1], counts[2])?;
+			}
+		}
+
+		writeln!(f, "\n{:<16}{:>12}{:>12}", "branch", "taken", "not taken")?;
+		for (instr, outcomes) in &self.branch_outcomes {
+			writeln!(f, "{:<16}{:>12}{:>12}", instr_name(*instr), outcomes[0], outcomes[1])?;
+		}
+
+		writeln!(f, "\n{:<16}{:>12}", "delay slot", "executed")?;
+		for (instr, count) in &self.delay_slots {
+			writeln!(f, "{:<16}{:>12}", instr_name(*instr), count)?;
+		}
+		if self.annulled_slots != 0 {
+			writeln!(f, "{:<16}{:>12}", "(annulled)", self.annulled_slots)?;
+		}
+
+		writeln!(f, "\n{:<16}{:>12}", "exception", "raised")?;
+		for (exc_code, count) in &self.exceptions {
+			writeln!(f, "{:<16}{:>12}", format!("{} ({})", exc_code_name(*exc_code), exc_code), count)?;
+		}
+		for (i, count) in self.irq_sources.iter().enumerate().filter(|(_, x)| **x != 0) {
+			writeln!(f, "{:<16}{:>12}", format!("Int: {}", irq_source_name(i)), count)?;
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use super::super::machine::tests::load_program;
+
+	#[test]
+	fn load_use_and_branch_coverage() {
+		let mut machine = load_program("
+			lw $1, 0($0)
+			addu $2, $1, $0
+			beq $2, $0, skip
+			sw $2, 4($0)
+		skip:
+			nop
+		", true);
+		machine.enable_coverage();
+		assert!(machine.run(100));
+		let coverage = machine.coverage().unwrap();
+		assert_eq!(coverage.instr_counts[&InstructionType::Nop], 1);
+		assert_eq!(coverage.distances[Producer::Load as usize][Consumer::Operand as usize], [1, 0, 0]);
+		assert_eq!(coverage.distances[Producer::Alu as usize][Consumer::BranchCompare as usize], [1, 0, 0]);
+		assert_eq!(coverage.distances[Producer::Alu as usize][Consumer::StoreData as usize], [0, 1, 0]);
+		assert_eq!(coverage.branch_outcomes[&InstructionType::Beq], [1, 0]);
+		assert_eq!(coverage.delay_slots[&InstructionType::Sw], 1);
+	}
+}
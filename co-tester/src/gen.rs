@@ -1,10 +1,31 @@
 use rand::prelude::*;
-use rand::rngs::ThreadRng;
+use rand::rngs::StdRng;
 use rand_distr::{Normal, Uniform};
 use strum_macros::{AsStaticStr, EnumIter, EnumString, EnumVariantNames};
+use super::coverage::{Consumer, HazardCounts, Producer};
 use super::machine::*;
+use super::profile::Profile;
+use super::timer::{TIMER_CTRL, TIMER_PRESET, TIMER_COUNT};
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq, AsStaticStr, EnumIter, EnumString, EnumVariantNames)]
+const MAX_STEPS: usize = 1 << 20;
+const LOOP_MAX_BODY_LEN: u32 = 4;
+const SUBROUTINE_MAX_COUNT: usize = 8;
+const SUBROUTINE_MAX_BODY_LEN: u32 = 12;
+const FRAME_SIZE: u32 = 8;
+const GRF_SP: u8 = 29;
+const GRF_RA: u8 = 31;
+const LL_SC_MAX_BODY_LEN: u32 = 4;
+const HAZARD_CELL_COUNT: u64 = 4 * 4 * 3;
+// Values around signed and unsigned overflow, the sign extension of immediates and the shift width.
+const EDGE_VALUES: [u32; 14] = [
+	0x7fffffff, 0x80000000, 0x80000001, 0x7ffffffe, 0xffffffff, 0x00000001, 0x00007fff, 0x00008000,
+	0x0000ffff, 0x00010000, 0xffff8000, 0xffff7fff, 0x0000001f, 0x00000020,
+];
+const EDGE_IMMS: [u16; 9] = [0x0000, 0x0001, 0x7ffe, 0x7fff, 0x8000, 0x8001, 0xfffe, 0xffff, 0x001f];
+const EDGE_SHIFTS: [u8; 3] = [0, 1, 31];
+const ADDR_SETUP_MAX_DISTANCE: u32 = 3;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, AsStaticStr, EnumIter, EnumString, EnumVariantNames)]
 #[strum(serialize_all = "kebab_case")]
 pub enum InstructionType {
 	Nop,
@@ -36,6 +57,12 @@ pub enum InstructionType {
 	Sb,
 	Sh,
 	Sw,
+	Lwl,
+	Lwr,
+	Swl,
+	Swr,
+	Ll,
+	Sc,
 	Slt,
 	Slti,
 	Sltu,
@@ -46,6 +73,10 @@ pub enum InstructionType {
 	Bltz,
 	Bgez,
 	Bgtz,
+	Bltzal,
+	Bgezal,
+	Beql,
+	Bnel,
 	J,
 	Jal,
 	Jr,
@@ -58,30 +89,177 @@ pub enum InstructionType {
 	Mfhi,
 	Mtlo,
 	Mthi,
+	Movz,
+	Movn,
+	Clz,
+	Clo,
+	Mul,
+	Madd,
+	Maddu,
+	Msub,
+	Msubu,
+	Teq,
+	Tne,
+	Tge,
+	Tgeu,
+	Tlt,
+	Tltu,
+	Teqi,
+	Tnei,
+	Tgei,
+	Tgeiu,
+	Tlti,
+	Tltiu,
+	Syscall,
+	Break,
+	Reserved,
 }
 
 impl InstructionType {
 	fn is_branch(&self) -> bool {
-		matches!(self, Self::Beq | Self::Bne | Self::Blez | Self::Bltz | Self::Bgez | Self::Bgtz | Self::J | Self::Jal | Self::Jr | Self::Jalr)
+		matches!(self, Self::Beq | Self::Bne | Self::Blez | Self::Bltz | Self::Bgez | Self::Bgtz |
+			Self::Bltzal | Self::Bgezal | Self::Beql | Self::Bnel | Self::J | Self::Jal | Self::Jr | Self::Jalr)
+	}
+
+	fn is_mem_access(&self) -> bool {
+		matches!(self, Self::Lb | Self::Lbu | Self::Lh | Self::Lhu | Self::Lw | Self::Sb | Self::Sh | Self::Sw |
+			Self::Lwl | Self::Lwr | Self::Swl | Self::Swr | Self::Ll | Self::Sc)
+	}
+
+	fn is_ll_sc(&self) -> bool {
+		matches!(self, Self::Ll | Self::Sc)
+	}
+
+	fn writes_hi_lo(&self) -> bool {
+		matches!(self, Self::Mult | Self::Multu | Self::Div | Self::Divu | Self::Mtlo | Self::Mthi) || self.is_accumulate()
+	}
+
+	fn is_accumulate(&self) -> bool {
+		matches!(self, Self::Madd | Self::Maddu | Self::Msub | Self::Msubu)
+	}
+
+	fn is_trap(&self) -> bool {
+		matches!(self, Self::Teq | Self::Tne | Self::Tge | Self::Tgeu | Self::Tlt | Self::Tltu |
+			Self::Teqi | Self::Tnei | Self::Tgei | Self::Tgeiu | Self::Tlti | Self::Tltiu)
+	}
+
+	fn needs_exception(&self) -> bool {
+		matches!(self, Self::Syscall | Self::Break | Self::Reserved) || self.is_trap()
+	}
+
+	// How the register written is produced, or None if none is.
+	pub fn producer(&self) -> Option<Producer> {
+		match self {
+			Self::Lb | Self::Lbu | Self::Lh | Self::Lhu | Self::Lw | Self::Lwl | Self::Lwr | Self::Ll => Some(Producer::Load),
+			Self::Mfhi | Self::Mflo => Some(Producer::HiLo),
+			Self::Bltzal | Self::Bgezal | Self::Jal | Self::Jalr => Some(Producer::Link),
+			Self::Add | Self::Addi | Self::Addu | Self::Addiu | Self::Sub | Self::Subu | Self::Sll | Self::Sllv |
+				Self::Srl | Self::Srlv | Self::Sra | Self::Srav | Self::And | Self::Andi | Self::Or | Self::Ori |
+				Self::Xor | Self::Xori | Self::Nor | Self::Lui | Self::Slt | Self::Slti | Self::Sltu | Self::Sltiu |
+				Self::Movz | Self::Movn | Self::Clz | Self::Clo | Self::Mul | Self::Sc => Some(Producer::Alu),
+			_ => None,
+		}
+	}
+
+	// How the first register picked by gen_grf_read_addr is read.
+	fn consumer(&self) -> Option<Consumer> {
+		match self {
+			Self::Sb | Self::Sh | Self::Sw | Self::Swl | Self::Swr => Some(Consumer::StoreData),
+			Self::Jr | Self::Jalr => Some(Consumer::JumpTarget),
+			Self::J | Self::Jal => None,
+			_ if self.is_branch() => Some(Consumer::BranchCompare),
+			Self::Nop | Self::Lui | Self::Mfhi | Self::Mflo | Self::Syscall | Self::Break | Self::Reserved => None,
+			_ if self.is_mem_access() => None,
+			_ => Some(Consumer::Operand),
+		}
 	}
 }
 
 trait RngExt {
-	fn rand_select<'a, 'b, T>(&'a mut self, data: &'b [T]) -> &'b T;
+	fn rand_select<'b, T>(&mut self, data: &'b [T]) -> &'b T;
 }
 
 impl<T: Rng> RngExt for T {
-	fn rand_select<'a, 'b, U>(&'a mut self, data: &'b [U]) -> &'b U {
+	fn rand_select<'b, U>(&mut self, data: &'b [U]) -> &'b U {
 		&data[self.gen_range(0..data.len())]
 	}
 }
 
+// A counted loop: the counter is set, the body runs, then the counter is decremented and checked by a backward branch.
+#[derive(Debug, Copy, Clone)]
+struct Loop {
+	start: u32,
+	end: u32,
+	counter: u8,
+}
+
+// A subroutine skipped over by a jump when it is defined, so its body is generated without being executed.
+#[derive(Debug, Copy, Clone)]
+struct Subroutine {
+	entry: u32,
+	body_end: u32,
+}
+
+// An ll and the sc on the same address after a short body without branches.
+#[derive(Debug, Copy, Clone)]
+struct LlSc {
+	base: u8,
+	offset: i16,
+	sc_addr: u32,
+}
+
+// A base address or jump target built ahead of the access or jr using it, with any ori still due at `ori_addr`.
+// Memory accesses add `offset` to the base.
+#[derive(Debug, Copy, Clone)]
+struct AddrSetup {
+	reg: u8,
+	value: u32,
+	offset: Option<i16>,
+	ori_addr: Option<u32>,
+	use_addr: u32,
+}
+
+// A boundary constant loaded by a lui and the ori after it.
+#[derive(Debug, Copy, Clone)]
+struct EdgeValue {
+	reg: u8,
+	lower: u16,
+	ori_addr: u32,
+}
+
+impl Subroutine {
+	fn end(&self) -> u32 {
+		self.body_end + WORD_SIZE as u32 * 3
+	}
+}
+
 pub struct InstructionGenerator<'a> {
 	machine: &'a mut MipsMachine,
-	instr_set: &'a [InstructionType],
+	instr_set: Vec<InstructionType>,
 	instr_set_no_branch: Vec<InstructionType>,
+	instr_set_loop_body: Vec<InstructionType>,
+	instr_set_subroutine_body: Vec<InstructionType>,
+	instr_set_accumulate: Vec<InstructionType>,
+	hi_lo_written: bool,
 	jump_limit: u32,
-	rng: ThreadRng,
+	loop_bound: u16,
+	cur_loop: Option<Loop>,
+	subroutine_enabled: bool,
+	stack_top: Option<u32>,
+	subroutines: Vec<u32>,
+	cur_subroutine: Option<Subroutine>,
+	ra_protected_addr: Option<u32>,
+	ll_sc_enabled: bool,
+	cur_ll_sc: Option<LlSc>,
+	cur_edge_value: Option<EdgeValue>,
+	cur_addr_setup: Option<AddrSetup>,
+	timer_enabled: bool,
+	seen_hazards: Option<HazardCounts>,
+	forced_read: Option<u8>,
+	forced_base: Option<(u8, i16)>,
+	forced_type: Option<InstructionType>,
+	profile: Profile,
+	rng: StdRng,
 	grf_addr_dist: Uniform<u8>,
 	grf_addr_excluded_dist: Uniform<u8>,
 	mem_addr_dist: Uniform<u32>,
@@ -90,55 +268,221 @@ pub struct InstructionGenerator<'a> {
 }
 
 impl<'a> InstructionGenerator<'a> {
-	pub fn new(machine: &'a mut MipsMachine, instr_set: &'a [InstructionType], instr_count: u32) -> Self {
+	pub fn new(
+		machine: &'a mut MipsMachine,
+		instr_set: &'a [InstructionType],
+		instr_count: u32,
+		timer_enabled: bool,
+		loop_bound: u16,
+		subroutine_enabled: bool,
+		seed: u64,
+	) -> Self {
 		let mem_size = machine.mem().len();
+		let exception_enabled = machine.exception_enabled();
+		let ll_sc_enabled = instr_set.contains(&InstructionType::Ll) && instr_set.contains(&InstructionType::Sc);
+		// ll and sc are only generated in pairs.
+		let instr_set = instr_set.iter()
+			.filter(|x| (exception_enabled || !x.needs_exception()) && !x.is_ll_sc())
+			.copied()
+			.collect::<Vec<_>>();
 		Self {
 			machine,
-			instr_set,
 			instr_set_no_branch: instr_set.iter()
 				.filter_map(|x| if x.is_branch() { None } else { Some(*x) })
 				.collect(),
+			// Later iterations run the body with different operands, so it must not fault without exception handling.
+			instr_set_loop_body: instr_set.iter()
+				.filter(|x| !x.is_branch() && (exception_enabled || !x.is_mem_access()))
+				.copied()
+				.collect(),
 			jump_limit: TEXT_START_ADDR + instr_count * WORD_SIZE as u32,
-			rng: rand::thread_rng(),
+			loop_bound: if instr_set.contains(&InstructionType::Addiu) && instr_set.contains(&InstructionType::Bne) {
+				loop_bound.min(i16::MAX as u16)
+			} else {
+				0
+			},
+			cur_loop: None,
+			// The body runs with whatever the registers hold at each call, so only instructions safe in any state are used.
+			instr_set_subroutine_body: instr_set.iter()
+				.filter(|x| !x.is_branch() && !x.is_mem_access())
+				.copied()
+				.collect(),
+			instr_set_accumulate: instr_set.iter()
+				.filter(|x| x.is_accumulate())
+				.copied()
+				.collect(),
+			hi_lo_written: false,
+			subroutine_enabled,
+			stack_top: None,
+			subroutines: Vec::new(),
+			cur_subroutine: None,
+			ra_protected_addr: None,
+			ll_sc_enabled,
+			cur_ll_sc: None,
+			cur_edge_value: None,
+			cur_addr_setup: None,
+			instr_set,
+			timer_enabled,
+			seen_hazards: None,
+			forced_read: None,
+			forced_base: None,
+			forced_type: None,
+			profile: Profile::default(),
+			rng: StdRng::seed_from_u64(seed),
 			grf_addr_dist: Uniform::new(0, GRF_SIZE as u8),
 			grf_addr_excluded_dist: Uniform::new(0, GRF_SIZE as u8 - 1),
 			mem_addr_dist: Uniform::new(0, (mem_size * WORD_SIZE) as u32),
-			imm_dist: Uniform::new_inclusive(0, u16::max_value()),
-			branch_dist: Normal::new(0f64, 5f64).unwrap(),
+			imm_dist: Uniform::new_inclusive(0, u16::MAX),
+			branch_dist: Normal::new(0f64, Profile::default().branch_dist_std_dev).unwrap(),
 		}
 	}
 
+	fn addr(&self) -> u32 {
+		self.machine.text_end()
+	}
+
 	fn grf_last_written(&self) -> Option<u8> {
 		self.machine.grf_log().last().map(|log| log.addr())
 	}
 
+	fn is_grf_reserved(&self, addr: u8) -> bool {
+		self.cur_loop.map(|x| x.counter) == Some(addr) ||
+			self.cur_ll_sc.map(|x| x.base) == Some(addr) ||
+			self.cur_addr_setup.map(|x| x.reg) == Some(addr) ||
+			self.stack_top.is_some() && addr == GRF_SP ||
+			self.ra_protected_addr == Some(self.addr()) && addr == GRF_RA
+	}
+
+	fn gen_grf_write_addr(&mut self) -> u8 {
+		loop {
+			let addr = self.rng.sample(self.grf_addr_dist);
+			if !self.is_grf_reserved(addr) {
+				return addr;
+			}
+		}
+	}
+
+	// The old value of the destination is merged in, so prefer one that was just written.
+	fn gen_grf_merge_addr(&mut self) -> u8 {
+		match self.grf_last_written() {
+			Some(addr) if !self.is_grf_reserved(addr) && self.rng.gen_bool(self.profile.grf_reuse_prob) => addr,
+			_ => self.gen_grf_write_addr(),
+		}
+	}
+
+	// Steers generation toward the dependencies not counted in `seen_hazards`, such as those of earlier tests.
+	pub fn with_feedback(mut self, seen_hazards: HazardCounts) -> Self {
+		if self.machine.coverage().is_none() {
+			self.machine.enable_coverage();
+		}
+		self.seen_hazards = Some(seen_hazards);
+		self
+	}
+
+	pub fn with_profile(mut self, profile: &Profile) -> Self {
+		self.branch_dist = Normal::new(0f64, profile.branch_dist_std_dev).unwrap();
+		self.profile = profile.clone();
+		self
+	}
+
 	fn gen_grf_read_addr(&mut self, exclude_addr: Option<u8>) -> u8 {
+		if let Some(addr) = self.forced_read.take().filter(|x| exclude_addr != Some(*x)) {
+			return addr;
+		}
 		let last_written = self.grf_last_written();
-		if let (Some(last_written), true) = (last_written, self.rng.gen_bool(0.5)) {
+		if let (Some(last_written), true) = (last_written, self.rng.gen_bool(self.profile.grf_reuse_prob)) {
 			if exclude_addr != Some(last_written) {
 				return last_written;
 			}
 		}
 		if let Some(exclude_addr) = exclude_addr {
-			let addr = self.rng.sample(&self.grf_addr_excluded_dist);
+			let addr = self.rng.sample(self.grf_addr_excluded_dist);
 			if addr >= exclude_addr { addr + 1 } else { addr }
 		} else {
-			self.rng.sample(&self.grf_addr_dist)
+			self.rng.sample(self.grf_addr_dist)
 		}
 	}
 
+	// Reads a register written recently in the way earlier tests have exercised least, or else writes one the way
+	// that leaves the most rare dependencies to read it with. A dependency is rare if it was counted less often
+	// than the average one.
+	fn gen_steered_type(&mut self, allow_branch: bool, jr_candidates: &[u8]) -> Option<InstructionType> {
+		let seen = self.seen_hazards?;
+		let coverage = self.machine.coverage()?;
+		if !self.rng.gen_bool(self.profile.feedback_prob) {
+			return None;
+		}
+		let local = coverage.hazards();
+		let count = |p: Producer, c: Consumer, d: usize| seen[p as usize][c as usize][d] + local[p as usize][c as usize][d];
+		let total = Producer::ALL.iter()
+			.flat_map(|p| Consumer::ALL.iter().flat_map(move |c| (0..3).map(move |d| count(*p, *c, d))))
+			.sum::<u64>();
+		let is_rare = |p: Producer, c: Consumer, d: usize| count(p, c, d) * HAZARD_CELL_COUNT <= total;
+		let is_allowed = |x: &InstructionType| (allow_branch || !x.is_branch()) &&
+			(!jr_candidates.is_empty() || !matches!(x, InstructionType::Jr | InstructionType::Jalr));
+		let mut reads = Vec::new();
+		for (reg, producer, distance) in coverage.pending_reads() {
+			for &consumer in Consumer::ALL.iter().filter(|c| is_rare(producer, **c, distance)) {
+				if consumer == Consumer::JumpTarget && !jr_candidates.contains(&reg) { continue; }
+				let types = self.instr_set.iter()
+					.filter(|x| x.consumer() == Some(consumer) && is_allowed(x))
+					.copied()
+					.collect::<Vec<_>>();
+				if !types.is_empty() {
+					reads.push((count(producer, consumer, distance), reg, types));
+				}
+			}
+		}
+		if let Some((_, reg, types)) = reads.iter().min_by_key(|(count, _, _)| *count) {
+			self.forced_read = Some(*reg);
+			return Some(self.profile.select(&mut self.rng, types));
+		}
+		// Whether a jump target can be read depends on the value written rather than the producer, and a link is
+		// always read first by its delay slot, which cannot branch.
+		let rare_count = |p: Producer| Consumer::ALL.iter()
+			.filter(|c| **c != Consumer::JumpTarget)
+			.map(|c| (0..3).filter(|d| is_rare(p, *c, *d))
+				.filter(|d| !(p == Producer::Link && *c == Consumer::BranchCompare && *d == 0))
+				.count())
+			.sum::<usize>();
+		let producer = *Producer::ALL.iter().max_by_key(|p| rare_count(**p))?;
+		if rare_count(producer) == 0 {
+			return None;
+		}
+		let types = self.instr_set.iter()
+			.filter(|x| x.producer() == Some(producer) && is_allowed(x))
+			.copied()
+			.collect::<Vec<_>>();
+		if types.is_empty() { None } else { Some(self.profile.select(&mut self.rng, &types)) }
+	}
+
 	fn gen_mem_read_addr(&mut self) -> u32 {
 		let last_written = self.machine.mem_log().last().map(|log| log.addr());
-		if let (Some(last_written), true) = (last_written, self.rng.gen_bool(0.3)) {
+		if let (Some(last_written), true) = (last_written, self.rng.gen_bool(self.profile.mem_reuse_prob)) {
 			last_written
 		} else {
-			self.rng.sample(&self.mem_addr_dist)
+			self.rng.sample(self.mem_addr_dist)
 		}
 	}
 
-	fn gen_base_and_offset(&mut self, addr_mask: u32) -> (u8, i16) {
-		let allow_exc = self.machine.exception_enabled() && self.rng.gen_bool(0.2);
-		let addr = self.gen_mem_read_addr();
+	fn gen_timer_addr(&mut self, allow_read_only: bool) -> u32 {
+		let regs: &[u32] = if allow_read_only {
+			&[TIMER_CTRL, TIMER_PRESET, TIMER_COUNT]
+		} else {
+			&[TIMER_CTRL, TIMER_PRESET]
+		};
+		*self.rng.rand_select(&TIMER_ADDRS) + *self.rng.rand_select(regs)
+	}
+
+	fn gen_base_and_offset(&mut self, addr_mask: u32, timer_access: Option<bool>) -> (u8, i16) {
+		if let Some(base_and_offset) = self.forced_base.take() {
+			return base_and_offset;
+		}
+		let allow_exc = self.machine.exception_enabled() && self.rng.gen_bool(self.profile.unaligned_prob);
+		let addr = match timer_access {
+			Some(allow_read_only) if self.timer_enabled && self.rng.gen_bool(self.profile.timer_access_prob) => self.gen_timer_addr(allow_read_only),
+			_ => self.gen_mem_read_addr(),
+		};
 		let addr = if allow_exc { addr } else { addr & addr_mask };
 		let grf = *self.machine.grf();
 		let candidates = grf.iter().enumerate().filter_map(|(id, value)| {
@@ -147,35 +491,369 @@ impl<'a> InstructionGenerator<'a> {
 			if offset_i16 as i64 == offset {
 				Some((id as u8, offset_i16))
 			} else if allow_exc {
-				Some((id as u8, self.rng.sample(&self.imm_dist) as i16))
+				Some((id as u8, self.rng.sample(self.imm_dist) as i16))
 			} else {
 				None
 			}
 		}).collect::<Vec<_>>();
 		let last_written = self.grf_last_written()
 			.and_then(|x| candidates.iter().find(|(y, _)| x == *y));
-		if let (Some(last_written), true) = (last_written, self.rng.gen_bool(0.5)) {
+		if let (Some(last_written), true) = (last_written, self.rng.gen_bool(self.profile.grf_reuse_prob)) {
 			*last_written
 		} else {
 			*self.rng.rand_select(&candidates)
 		}
 	}
 
+	fn gen_move_cond_addr(&mut self) -> u8 {
+		// Pick the condition by its value, most registers are non-zero otherwise.
+		let want_zero = self.rng.gen_bool(self.profile.zero_cond_prob);
+		let candidates = (0..GRF_SIZE as u8)
+			.filter(|x| (self.machine.grf()[*x as usize] == 0) == want_zero)
+			.collect::<Vec<_>>();
+		if candidates.is_empty() {
+			self.gen_grf_read_addr(None)
+		} else {
+			*self.rng.rand_select(&candidates)
+		}
+	}
+
+	// Random operands almost never compare equal, so sometimes compare against an equal value.
+	fn gen_trap_operands(&mut self) -> (u8, u8) {
+		let rs = self.gen_grf_read_addr(None);
+		if self.rng.gen_bool(self.profile.trap_equal_prob) {
+			let value = self.machine.grf()[rs as usize];
+			let candidates = (0..GRF_SIZE as u8)
+				.filter(|x| self.machine.grf()[*x as usize] == value)
+				.collect::<Vec<_>>();
+			(rs, *self.rng.rand_select(&candidates))
+		} else {
+			(rs, self.gen_grf_read_addr(None))
+		}
+	}
+
+	fn gen_trap_imm_operands(&mut self) -> (u8, i16) {
+		if self.rng.gen_bool(self.profile.trap_equal_prob) {
+			// Compare a register that fits in the immediate against its value or a neighbour of it.
+			let candidates = (0..GRF_SIZE as u8)
+				.filter(|x| self.machine.grf()[*x as usize] as i16 as u32 == self.machine.grf()[*x as usize])
+				.collect::<Vec<_>>();
+			let rs = *self.rng.rand_select(&candidates);
+			let imm = i16::saturating_add(self.machine.grf()[rs as usize] as i16, self.rng.gen_range(-1..=1));
+			(rs, imm)
+		} else {
+			(self.gen_grf_read_addr(None), self.gen_imm() as i16)
+		}
+	}
+
+	fn gen_imm(&mut self) -> u16 {
+		if self.rng.gen_bool(self.profile.edge_imm_prob) {
+			*self.rng.rand_select(&EDGE_IMMS)
+		} else {
+			self.rng.sample(self.imm_dist)
+		}
+	}
+
+	fn gen_shift_amount(&mut self) -> u8 {
+		if self.rng.gen_bool(self.profile.edge_imm_prob) {
+			*self.rng.rand_select(&EDGE_SHIFTS)
+		} else {
+			self.rng.gen_range(0..32)
+		}
+	}
+
+	// Decoders should ignore the code field, but most programs leave it zero.
+	fn gen_exc_code_field(&mut self) -> u32 {
+		if self.rng.gen_bool(self.profile.zero_code_field_prob) { 0 } else { self.rng.gen_range(0..1 << 20) }
+	}
+
+	fn gen_reserved_code(&mut self) -> u32 {
+		// Only the fields that select an instruction are reserved, so that decoders ignoring the other fields reject it too.
+		let (shift, len, prefix) = *self.rng.rand_select(&[(26, 6, 0), (0, 6, 0), (16, 5, 0b000001 << 26), (0, 6, 0b011100 << 26)]);
+		let mask = (1u32 << len) - 1;
+		let is_defined = |x: u32| decode_instruction(prefix | x << shift).is_ok();
+		let defined = (0..=mask).filter(|x| is_defined(*x)).collect::<Vec<_>>();
+		let selector = loop {
+			let selector = if self.rng.gen_bool(self.profile.reserved_near_miss_prob) {
+				// A near miss of a real encoding.
+				*self.rng.rand_select(&defined) ^ 1 << self.rng.gen_range(0..len)
+			} else {
+				self.rng.gen_range(0..=mask)
+			};
+			if !is_defined(selector) { break selector; }
+		};
+		prefix | selector << shift | self.rng.gen::<u32>() & !(0b111111 << 26 | mask << shift)
+	}
+
 	fn gen_branch_offset(&mut self) -> i16 {
-		let mut offset = (self.rng.sample(&self.branch_dist) as i16).abs();
+		let mut offset = (self.rng.sample(self.branch_dist) as i16).abs();
 		if !self.machine.exception_enabled() {
 			offset += 1;
 		}
-		let addr = self.machine.pc() + (offset as u32 + 1) * WORD_SIZE as u32;
+		let addr = self.addr() + (offset as u32 + 1) * WORD_SIZE as u32;
 		if addr < self.jump_limit {
 			offset
 		} else {
-			((self.jump_limit - self.machine.pc() - 1) / WORD_SIZE as u32) as i16
+			((self.jump_limit - self.addr() - 1) / WORD_SIZE as u32) as i16
 		}
 	}
 
 	fn gen_jump_addr(&mut self) -> u32 {
-		self.machine.pc() / WORD_SIZE as u32 + self.gen_branch_offset() as u32 + 1
+		self.addr() / WORD_SIZE as u32 + self.gen_branch_offset() as u32 + 1
+	}
+
+	fn gen_loop_instr(&mut self, can_start: bool) -> Option<Box<dyn Instruction>> {
+		let addr = self.addr();
+		match self.cur_loop {
+			Some(cur_loop) if addr > cur_loop.end + WORD_SIZE as u32 * 2 => self.cur_loop = None,
+			_ => (),
+		}
+		match self.cur_loop {
+			None => {
+				let body_len = self.rng.gen_range(0..=LOOP_MAX_BODY_LEN);
+				let delay_slot_addr = addr + (body_len + 3) * WORD_SIZE as u32;
+				if !can_start || self.loop_bound == 0 || self.instr_set_loop_body.is_empty() ||
+					delay_slot_addr >= self.jump_limit || !self.rng.gen_bool(self.profile.loop_prob) {
+					return None;
+				}
+				let counter = loop {
+					// Leave $k0 and $k1 to the exception handler, and $sp to subroutines.
+					let counter = self.rng.gen_range(1..GRF_SIZE as u8);
+					if !(matches!(counter, 26 | 27) || self.stack_top.is_some() && counter == GRF_SP) { break counter; }
+				};
+				let start = addr + WORD_SIZE as u32;
+				self.cur_loop = Some(Loop { start, end: start + body_len * WORD_SIZE as u32, counter });
+				Some(Box::new(AddiuInstr {
+					rs: 0,
+					rt: counter,
+					imm: self.rng.gen_range(1..=self.loop_bound) as i16,
+				}))
+			}
+			Some(cur_loop) if addr == cur_loop.end => Some(Box::new(AddiuInstr {
+				rs: cur_loop.counter,
+				rt: cur_loop.counter,
+				imm: -1,
+			})),
+			Some(cur_loop) if addr == cur_loop.end + WORD_SIZE as u32 => Some(Box::new(BneInstr {
+				rs: cur_loop.counter,
+				rt: 0,
+				offset: ((cur_loop.start as i64 - addr as i64) / WORD_SIZE as i64 - 1) as i16,
+			})),
+			Some(_) => None,
+		}
+	}
+
+	fn gen_subroutine_instr(&mut self, can_start: bool) -> Option<Box<dyn Instruction>> {
+		let addr = self.addr();
+		if self.stack_top.is_none() {
+			// Keep the stack clear of the area the exception handler saves registers to.
+			let stack_top = (self.machine.mem().len() * WORD_SIZE).min(0xfff8) as u32 & !(FRAME_SIZE - 1);
+			if !self.subroutine_enabled || !can_start || stack_top < 0x100 + FRAME_SIZE * SUBROUTINE_MAX_COUNT as u32 {
+				return None;
+			}
+			self.stack_top = Some(stack_top);
+			return Some(Box::new(OriInstr { rs: 0, rt: GRF_SP, imm: stack_top as u16 }));
+		}
+		match self.cur_subroutine {
+			Some(cur_subroutine) if addr >= cur_subroutine.end() => {
+				self.subroutines.push(cur_subroutine.entry);
+				self.cur_subroutine = None;
+			}
+			_ => (),
+		}
+		let frame_size = FRAME_SIZE as i16;
+		match self.cur_subroutine {
+			None => {
+				if !can_start || addr + WORD_SIZE as u32 >= self.jump_limit {
+					return None;
+				}
+				if !self.subroutines.is_empty() && self.rng.gen_bool(self.profile.call_prob) {
+					self.ra_protected_addr = Some(addr + WORD_SIZE as u32);
+					let entry = *self.rng.rand_select(&self.subroutines);
+					return Some(Box::new(JalInstr { addr: entry / WORD_SIZE as u32 }));
+				}
+				let body_len = self.rng.gen_range(0..=SUBROUTINE_MAX_BODY_LEN);
+				let entry = addr + WORD_SIZE as u32 * 2;
+				let subroutine = Subroutine { entry, body_end: entry + (body_len + 2) * WORD_SIZE as u32 };
+				if self.subroutines.len() >= SUBROUTINE_MAX_COUNT || self.instr_set_subroutine_body.is_empty() ||
+					subroutine.end() >= self.jump_limit || !self.rng.gen_bool(self.profile.subroutine_prob) {
+					return None;
+				}
+				self.cur_subroutine = Some(subroutine);
+				Some(Box::new(JInstr { addr: subroutine.end() / WORD_SIZE as u32 }))
+			}
+			Some(cur_subroutine) => {
+				let epilogue_id = addr.wrapping_sub(cur_subroutine.body_end) / WORD_SIZE as u32;
+				if addr + WORD_SIZE as u32 == cur_subroutine.entry {
+					// The delay slot of the jump over the subroutine must not fault into the entry.
+					Some(Box::new(NopInstr))
+				} else if addr == cur_subroutine.entry {
+					Some(Box::new(AddiuInstr { rs: GRF_SP, rt: GRF_SP, imm: -frame_size }))
+				} else if addr == cur_subroutine.entry + WORD_SIZE as u32 {
+					Some(Box::new(SwInstr { base: GRF_SP, rt: GRF_RA, offset: frame_size - WORD_SIZE as i16 }))
+				} else if epilogue_id < 3 {
+					// Reload $ra right before jr $ra, with or without a delay slot to restore $sp in.
+					let epilogue: Vec<Box<dyn Instruction>> = if self.machine.delayed_branching() {vec![
+						Box::new(LwInstr { base: GRF_SP, rt: GRF_RA, offset: frame_size - WORD_SIZE as i16 }),
+						Box::new(JrInstr { rs: GRF_RA }),
+						Box::new(AddiuInstr { rs: GRF_SP, rt: GRF_SP, imm: frame_size }),
+					]} else {vec![
+						Box::new(AddiuInstr { rs: GRF_SP, rt: GRF_SP, imm: frame_size }),
+						Box::new(LwInstr { base: GRF_SP, rt: GRF_RA, offset: -(WORD_SIZE as i16) }),
+						Box::new(JrInstr { rs: GRF_RA }),
+					]};
+					epilogue.into_iter().nth(epilogue_id as usize)
+				} else if addr > cur_subroutine.entry && addr + WORD_SIZE as u32 * 2 <= cur_subroutine.body_end &&
+					self.ra_protected_addr != Some(addr) && !self.subroutines.is_empty() && self.rng.gen_bool(self.profile.nested_call_prob) {
+					self.ra_protected_addr = Some(addr + WORD_SIZE as u32);
+					let entry = *self.rng.rand_select(&self.subroutines);
+					Some(Box::new(JalInstr { addr: entry / WORD_SIZE as u32 }))
+				} else {
+					None
+				}
+			}
+		}
+	}
+
+	fn gen_ll_sc_instr(&mut self, can_start: bool) -> Option<Box<dyn Instruction>> {
+		let addr = self.addr();
+		match self.cur_ll_sc {
+			Some(ll_sc) if addr == ll_sc.sc_addr => {
+				self.cur_ll_sc = None;
+				let rt = self.gen_grf_write_addr();
+				Some(Box::new(ScInstr { base: ll_sc.base, rt, offset: ll_sc.offset }))
+			}
+			Some(_) => None,
+			None => {
+				let sc_addr = addr + (self.rng.gen_range(0..=LL_SC_MAX_BODY_LEN) + 1) * WORD_SIZE as u32;
+				if !self.ll_sc_enabled || !can_start || sc_addr >= self.jump_limit || !self.rng.gen_bool(self.profile.ll_sc_prob) {
+					return None;
+				}
+				let (base, offset) = self.gen_base_and_offset(!0b11, None);
+				// Reserve the base before picking rt, so the sc sees the same address.
+				self.cur_ll_sc = Some(LlSc { base, offset, sc_addr });
+				let rt = self.gen_grf_write_addr();
+				Some(Box::new(LlInstr { base, rt, offset }))
+			}
+		}
+	}
+
+	fn gen_edge_value_instr(&mut self, can_start: bool) -> Option<Box<dyn Instruction>> {
+		let addr = self.addr();
+		match self.cur_edge_value {
+			Some(edge_value) if addr == edge_value.ori_addr => {
+				let EdgeValue { reg, lower, .. } = edge_value;
+				Some(Box::new(OriInstr { rs: reg, rt: reg, imm: lower }))
+			}
+			Some(edge_value) => {
+				// Have the next instruction read the value.
+				self.cur_edge_value = None;
+				self.forced_read = Some(edge_value.reg);
+				None
+			}
+			None => {
+				let ori_addr = addr + WORD_SIZE as u32;
+				let is_enabled = self.instr_set.contains(&InstructionType::Lui) && self.instr_set.contains(&InstructionType::Ori);
+				if !is_enabled || !can_start || ori_addr + (WORD_SIZE as u32) >= self.jump_limit ||
+					!self.rng.gen_bool(self.profile.edge_value_prob) {
+					return None;
+				}
+				let reg = loop {
+					let reg = self.gen_grf_write_addr();
+					if reg != 0 { break reg; }
+				};
+				let value = *self.rng.rand_select(&EDGE_VALUES);
+				self.cur_edge_value = Some(EdgeValue { reg, lower: value as u16, ori_addr });
+				Some(Box::new(LuiInstr { rt: reg, imm: (value >> 16) as u16 }))
+			}
+		}
+	}
+
+	// Builds the base of a memory access, or a jr target if no register holds one, a few instructions before the
+	// access or jr, so that it does not have to use whatever register happens to be close.
+	fn gen_addr_setup_instr(&mut self, can_start: bool, has_jr_candidates: bool) -> Option<Box<dyn Instruction>> {
+		let addr = self.addr();
+		match self.cur_addr_setup {
+			Some(setup) if setup.ori_addr == Some(addr) => {
+				Some(Box::new(OriInstr { rs: setup.reg, rt: setup.reg, imm: setup.value as u16 }))
+			}
+			Some(setup) if addr == setup.use_addr => {
+				self.cur_addr_setup = None;
+				// Faults in between may have left the pc elsewhere, in which case the setup is wasted.
+				if self.machine.pc() != addr || self.machine.grf()[setup.reg as usize] != setup.value {
+					return None;
+				}
+				let types = self.instr_set.iter()
+					.filter(|x| if setup.offset.is_some() {
+						x.is_mem_access()
+					} else {
+						matches!(x, InstructionType::Jr | InstructionType::Jalr)
+					})
+					.copied()
+					.collect::<Vec<_>>();
+				self.forced_type = Some(self.profile.select(&mut self.rng, &types));
+				match setup.offset {
+					Some(offset) => self.forced_base = Some((setup.reg, offset)),
+					None => self.forced_read = Some(setup.reg),
+				}
+				None
+			}
+			Some(_) => None,
+			None => {
+				let has = |x| self.instr_set.contains(&x);
+				let mem_enabled = self.instr_set.iter().any(|x| x.is_mem_access());
+				let jr_enabled = !has_jr_candidates && (has(InstructionType::Jr) || has(InstructionType::Jalr));
+				let lui_enabled = has(InstructionType::Lui) && has(InstructionType::Ori);
+				let addiu_enabled = has(InstructionType::Addiu);
+				if !can_start || !(mem_enabled || jr_enabled) || !(lui_enabled || addiu_enabled) ||
+					!self.rng.gen_bool(self.profile.addr_setup_prob) {
+					return None;
+				}
+				let distance = self.rng.gen_range(1..=ADDR_SETUP_MAX_DISTANCE);
+				// Leave room for the ori and the delay slot of a jr.
+				let use_addr = addr + (distance + 1) * WORD_SIZE as u32;
+				if use_addr + WORD_SIZE as u32 >= self.jump_limit {
+					return None;
+				}
+				let (value, offset) = if mem_enabled && (!jr_enabled || self.rng.gen_bool(self.profile.mem_setup_prob)) {
+					let offset = self.gen_imm() as i16;
+					let target = self.gen_mem_read_addr() & !0b11;
+					(target.wrapping_sub(offset as u32), Some(offset))
+				} else {
+					let mut offset = (self.rng.sample(self.branch_dist) as i16).unsigned_abs() as u32;
+					if !self.machine.exception_enabled() {
+						offset += 1;
+					}
+					let target = use_addr + (offset + 1) * WORD_SIZE as u32;
+					(target.min(self.jump_limit - WORD_SIZE as u32), None)
+				};
+				let reg = loop {
+					// $k0 and $k1 belong to the exception handler.
+					let reg = self.gen_grf_write_addr();
+					if !matches!(reg, 0 | 26 | 27) { break reg; }
+				};
+				let fits_imm = value as i16 as u32 == value;
+				let (instr, ori_addr): (Box<dyn Instruction>, _) = if fits_imm && addiu_enabled && (!lui_enabled || self.rng.gen_bool(self.profile.addiu_setup_prob)) {
+					(Box::new(AddiuInstr { rs: 0, rt: reg, imm: value as i16 }), None)
+				} else if lui_enabled {
+					let ori_addr = if value as u16 == 0 { None } else { Some(addr + WORD_SIZE as u32) };
+					(Box::new(LuiInstr { rt: reg, imm: (value >> 16) as u16 }), ori_addr)
+				} else {
+					return None;
+				};
+				// Without an ori the use moves up, keeping the chosen distance from the last setup instruction.
+				let use_addr = if ori_addr.is_some() { use_addr } else { use_addr - WORD_SIZE as u32 };
+				self.cur_addr_setup = Some(AddrSetup { reg, value, offset, ori_addr, use_addr });
+				Some(instr)
+			}
+		}
+	}
+
+	fn emit(&mut self, instr: Box<dyn Instruction>) -> Box<dyn Instruction> {
+		self.machine.push_text(instr.to_machine_code());
+		let finished = self.machine.run(MAX_STEPS);
+		debug_assert!(finished);
+		instr
 	}
 }
 
@@ -183,194 +861,242 @@ impl Iterator for InstructionGenerator<'_> {
 	type Item = Box<dyn Instruction>;
 
 	fn next(&mut self) -> Option<Self::Item> {
-		if self.machine.pc() >= self.jump_limit { return None; }
-		if self.machine.exception_enabled() && self.rng.gen_bool(0.1) {
+		if self.addr() >= self.jump_limit { return None; }
+		let hi_lo_written = std::mem::replace(&mut self.hi_lo_written, false);
+		// Interrupts between an ll and its sc must break the link.
+		let irq_prob = if self.cur_ll_sc.is_some() { self.profile.ll_sc_irq_prob } else { self.profile.irq_prob };
+		if self.machine.pc() == self.addr() && self.machine.exception_enabled() && self.rng.gen_bool(irq_prob) {
 			self.machine.interrupt();
+			self.machine.run(MAX_STEPS);
 		}
-		let allow_unaligned_jr = self.machine.exception_enabled() && self.rng.gen_bool(0.1);
+		let is_executed = self.machine.pc() == self.addr();
+		let allow_unaligned_jr = self.machine.exception_enabled() && self.rng.gen_bool(self.profile.unaligned_jr_prob);
 		let jr_candidates = self.machine.grf().iter().enumerate()
 			.filter_map(|(i, x)| {
-				let is_in_range = (self.machine.pc() + 1..self.jump_limit).contains(x);
+				let is_in_range = (self.addr() + 1..self.jump_limit).contains(x);
 				let is_aligned = x / WORD_SIZE as u32 * WORD_SIZE as u32 == *x;
 				if is_in_range && (allow_unaligned_jr || is_aligned) { Some(i as u8) } else { None }
 			})
 			.collect::<Vec<_>>();
-		let is_last_instr = self.machine.pc() + WORD_SIZE as u32 == self.jump_limit;
-		let instr_type = match (self.machine.state(), is_last_instr) {
-			(MachineState::InDelaySlot(_), _) | (_, true) => *self.rng.rand_select(&self.instr_set_no_branch),
+		let is_last_instr = self.addr() + WORD_SIZE as u32 == self.jump_limit;
+		let in_delay_slot = is_executed && matches!(self.machine.state(), MachineState::InDelaySlot(_) | MachineState::AnnulledSlot);
+		let can_start = is_executed && !in_delay_slot && self.ra_protected_addr != Some(self.addr());
+		let is_idle = self.cur_loop.is_none() && self.cur_subroutine.is_none() && self.cur_ll_sc.is_none();
+		if let Some(instr) = self.gen_addr_setup_instr(can_start && is_idle && self.cur_edge_value.is_none(), !jr_candidates.is_empty()) {
+			return Some(self.emit(instr));
+		}
+		let can_start = can_start && self.cur_addr_setup.is_none() && self.forced_type.is_none();
+		if let Some(instr) = self.gen_edge_value_instr(can_start && is_idle) {
+			return Some(self.emit(instr));
+		}
+		let can_start = can_start && self.cur_edge_value.is_none();
+		if let Some(instr) = self.gen_ll_sc_instr(can_start && self.cur_loop.is_none() && self.cur_subroutine.is_none()) {
+			return Some(self.emit(instr));
+		}
+		let can_start = can_start && self.cur_ll_sc.is_none();
+		if let Some(instr) = self.gen_loop_instr(can_start && self.cur_subroutine.is_none()) {
+			return Some(self.emit(instr));
+		}
+		if let Some(instr) = self.gen_subroutine_instr(can_start && self.cur_loop.is_none()) {
+			return Some(self.emit(instr));
+		}
+		let steered_type = if self.cur_loop.is_some() || self.cur_subroutine.is_some() || self.cur_ll_sc.is_some() ||
+			self.cur_addr_setup.is_some() || self.forced_type.is_some() {
+			None
+		} else {
+			self.gen_steered_type(!in_delay_slot && !is_last_instr, &jr_candidates)
+		};
+		let instr_type = match (in_delay_slot, is_last_instr) {
+			_ if self.cur_loop.is_some() => self.profile.select(&mut self.rng, &self.instr_set_loop_body),
+			_ if self.cur_subroutine.is_some() => self.profile.select(&mut self.rng, &self.instr_set_subroutine_body),
+			_ if self.cur_ll_sc.is_some() || self.cur_addr_setup.is_some() => self.profile.select(&mut self.rng, &self.instr_set_no_branch),
+			(true, _) | (_, true) => self.profile.select(&mut self.rng, &self.instr_set_no_branch),
 			_ => {
 				if jr_candidates.is_empty() {
 					let instr_set = self.instr_set.iter()
-						.filter(|x| !matches!(x, InstructionType::Jr | InstructionType::Jalr)).collect::<Vec<_>>();
-					**self.rng.rand_select(&instr_set)
+						.filter(|x| !matches!(x, InstructionType::Jr | InstructionType::Jalr))
+						.copied()
+						.collect::<Vec<_>>();
+					self.profile.select(&mut self.rng, &instr_set)
 				} else {
-					*self.rng.rand_select(self.instr_set)
+					self.profile.select(&mut self.rng, &self.instr_set)
 				}
 			}
 		};
+		// Chain accumulations onto the result of the previous HI/LO write.
+		let instr_type = if let Some(forced_type) = self.forced_type.take() {
+			forced_type
+		} else if let Some(steered_type) = steered_type {
+			steered_type
+		} else if hi_lo_written && !self.instr_set_accumulate.is_empty() && self.rng.gen_bool(self.profile.accumulate_prob) {
+			self.profile.select(&mut self.rng, &self.instr_set_accumulate)
+		} else {
+			instr_type
+		};
+		self.hi_lo_written = instr_type.writes_hi_lo();
 		let instr: Box<dyn Instruction> = match instr_type {
 			InstructionType::Nop => Box::new(NopInstr),
 			InstructionType::Add => Box::new(AddInstr {
 				rs: self.gen_grf_read_addr(None),
 				rt: self.gen_grf_read_addr(None),
-				rd: self.rng.sample(&self.grf_addr_dist),
+				rd: self.gen_grf_write_addr(),
 			}),
 			InstructionType::Addi => Box::new(AddiInstr {
 				rs: self.gen_grf_read_addr(None),
-				rt: self.rng.sample(&self.grf_addr_dist),
-				imm: self.rng.sample(&self.imm_dist) as i16,
+				rt: self.gen_grf_write_addr(),
+				imm: self.gen_imm() as i16,
 			}),
 			InstructionType::Addu => Box::new(AdduInstr {
 				rs: self.gen_grf_read_addr(None),
 				rt: self.gen_grf_read_addr(None),
-				rd: self.rng.sample(&self.grf_addr_dist),
+				rd: self.gen_grf_write_addr(),
 			}),
 			InstructionType::Addiu => Box::new(AddiuInstr {
 				rs: self.gen_grf_read_addr(None),
-				rt: self.rng.sample(&self.grf_addr_dist),
-				imm: self.rng.sample(&self.imm_dist) as i16,
+				rt: self.gen_grf_write_addr(),
+				imm: self.gen_imm() as i16,
 			}),
 			InstructionType::Sub => Box::new(SubInstr {
 				rs: self.gen_grf_read_addr(None),
 				rt: self.gen_grf_read_addr(None),
-				rd: self.rng.sample(&self.grf_addr_dist),
+				rd: self.gen_grf_write_addr(),
 			}),
 			InstructionType::Subu => Box::new(SubuInstr {
 				rs: self.gen_grf_read_addr(None),
 				rt: self.gen_grf_read_addr(None),
-				rd: self.rng.sample(&self.grf_addr_dist),
+				rd: self.gen_grf_write_addr(),
 			}),
 			InstructionType::Sll => Box::new(SllInstr {
 				rt: self.gen_grf_read_addr(None),
-				rd: self.rng.sample(&self.grf_addr_dist),
-				sa: self.rng.gen_range(0..32),
+				rd: self.gen_grf_write_addr(),
+				sa: self.gen_shift_amount(),
 			}),
 			InstructionType::Sllv => Box::new(SllvInstr {
 				rs: self.gen_grf_read_addr(None),
 				rt: self.gen_grf_read_addr(None),
-				rd: self.rng.sample(&self.grf_addr_dist),
+				rd: self.gen_grf_write_addr(),
 			}),
 			InstructionType::Srl => Box::new(SrlInstr {
 				rt: self.gen_grf_read_addr(None),
-				rd: self.rng.sample(&self.grf_addr_dist),
-				sa: self.rng.gen_range(0..32),
+				rd: self.gen_grf_write_addr(),
+				sa: self.gen_shift_amount(),
 			}),
 			InstructionType::Srlv => Box::new(SrlvInstr {
 				rs: self.gen_grf_read_addr(None),
 				rt: self.gen_grf_read_addr(None),
-				rd: self.rng.sample(&self.grf_addr_dist),
+				rd: self.gen_grf_write_addr(),
 			}),
 			InstructionType::Sra => Box::new(SraInstr {
 				rt: self.gen_grf_read_addr(None),
-				rd: self.rng.sample(&self.grf_addr_dist),
-				sa: self.rng.gen_range(0..32),
+				rd: self.gen_grf_write_addr(),
+				sa: self.gen_shift_amount(),
 			}),
 			InstructionType::Srav => Box::new(SravInstr {
 				rs: self.gen_grf_read_addr(None),
 				rt: self.gen_grf_read_addr(None),
-				rd: self.rng.sample(&self.grf_addr_dist),
+				rd: self.gen_grf_write_addr(),
 			}),
 			InstructionType::Slt => Box::new(SltInstr {
 				rs: self.gen_grf_read_addr(None),
 				rt: self.gen_grf_read_addr(None),
-				rd: self.rng.sample(&self.grf_addr_dist),
+				rd: self.gen_grf_write_addr(),
 			}),
 			InstructionType::Slti => Box::new(SltiInstr {
 				rs: self.gen_grf_read_addr(None),
-				rt: self.rng.sample(&self.grf_addr_dist),
-				imm: self.rng.sample(&self.imm_dist) as i16,
+				rt: self.gen_grf_write_addr(),
+				imm: self.gen_imm() as i16,
 			}),
 			InstructionType::Sltu => Box::new(SltuInstr {
 				rs: self.gen_grf_read_addr(None),
 				rt: self.gen_grf_read_addr(None),
-				rd: self.rng.sample(&self.grf_addr_dist),
+				rd: self.gen_grf_write_addr(),
 			}),
 			InstructionType::Sltiu => Box::new(SltiuInstr {
 				rs: self.gen_grf_read_addr(None),
-				rt: self.rng.sample(&self.grf_addr_dist),
-				imm: self.rng.sample(&self.imm_dist) as i16,
+				rt: self.gen_grf_write_addr(),
+				imm: self.gen_imm() as i16,
 			}),
 			InstructionType::And => Box::new(AndInstr {
 				rs: self.gen_grf_read_addr(None),
 				rt: self.gen_grf_read_addr(None),
-				rd: self.rng.sample(&self.grf_addr_dist),
+				rd: self.gen_grf_write_addr(),
 			}),
 			InstructionType::Andi => Box::new(AndiInstr {
 				rs: self.gen_grf_read_addr(None),
-				rt: self.rng.sample(&self.grf_addr_dist),
-				imm: self.rng.sample(&self.imm_dist),
+				rt: self.gen_grf_write_addr(),
+				imm: self.gen_imm(),
 			}),
 			InstructionType::Or => Box::new(OrInstr {
 				rs: self.gen_grf_read_addr(None),
 				rt: self.gen_grf_read_addr(None),
-				rd: self.rng.sample(&self.grf_addr_dist),
+				rd: self.gen_grf_write_addr(),
 			}),
 			InstructionType::Ori => Box::new(OriInstr {
 				rs: self.gen_grf_read_addr(None),
-				rt: self.rng.sample(&self.grf_addr_dist),
-				imm: self.rng.sample(&self.imm_dist),
+				rt: self.gen_grf_write_addr(),
+				imm: self.gen_imm(),
 			}),
 			InstructionType::Xor => Box::new(XorInstr {
 				rs: self.gen_grf_read_addr(None),
 				rt: self.gen_grf_read_addr(None),
-				rd: self.rng.sample(&self.grf_addr_dist),
+				rd: self.gen_grf_write_addr(),
 			}),
 			InstructionType::Xori => Box::new(XoriInstr {
 				rs: self.gen_grf_read_addr(None),
-				rt: self.rng.sample(&self.grf_addr_dist),
-				imm: self.rng.sample(&self.imm_dist),
+				rt: self.gen_grf_write_addr(),
+				imm: self.gen_imm(),
 			}),
 			InstructionType::Nor => Box::new(NorInstr {
 				rs: self.gen_grf_read_addr(None),
 				rt: self.gen_grf_read_addr(None),
-				rd: self.rng.sample(&self.grf_addr_dist),
+				rd: self.gen_grf_write_addr(),
 			}),
 			InstructionType::Lui => Box::new(LuiInstr {
-				rt: self.rng.sample(&self.grf_addr_dist),
-				imm: self.rng.sample(&self.imm_dist),
+				rt: self.gen_grf_write_addr(),
+				imm: self.gen_imm(),
 			}),
 			InstructionType::Lb => {
-				let (base, offset) = self.gen_base_and_offset(!0);
+				let (base, offset) = self.gen_base_and_offset(!0, None);
 				Box::new(LbInstr {
 					base,
-					rt: self.rng.sample(&self.grf_addr_dist),
+					rt: self.gen_grf_write_addr(),
 					offset,
 				})
 			}
 			InstructionType::Lbu => {
-				let (base, offset) = self.gen_base_and_offset(!0);
+				let (base, offset) = self.gen_base_and_offset(!0, None);
 				Box::new(LbuInstr {
 					base,
-					rt: self.rng.sample(&self.grf_addr_dist),
+					rt: self.gen_grf_write_addr(),
 					offset,
 				})
 			}
 			InstructionType::Lh => {
-				let (base, offset) = self.gen_base_and_offset(!0b1);
+				let (base, offset) = self.gen_base_and_offset(!0b1, None);
 				Box::new(LhInstr {
 					base,
-					rt: self.rng.sample(&self.grf_addr_dist),
+					rt: self.gen_grf_write_addr(),
 					offset,
 				})
 			}
 			InstructionType::Lhu => {
-				let (base, offset) = self.gen_base_and_offset(!0b1);
+				let (base, offset) = self.gen_base_and_offset(!0b1, None);
 				Box::new(LhuInstr {
 					base,
-					rt: self.rng.sample(&self.grf_addr_dist),
+					rt: self.gen_grf_write_addr(),
 					offset,
 				})
 			}
 			InstructionType::Lw => {
-				let (base, offset) = self.gen_base_and_offset(!0b11);
+				let (base, offset) = self.gen_base_and_offset(!0b11, Some(true));
 				Box::new(LwInstr {
 					base,
-					rt: self.rng.sample(&self.grf_addr_dist),
+					rt: self.gen_grf_write_addr(),
 					offset,
 				})
 			}
 			InstructionType::Sb => {
-				let (base, offset) = self.gen_base_and_offset(!0);
+				let (base, offset) = self.gen_base_and_offset(!0, None);
 				Box::new(SbInstr {
 					base,
 					rt: self.gen_grf_read_addr(None),
@@ -378,21 +1104,55 @@ impl Iterator for InstructionGenerator<'_> {
 				})
 			}
 			InstructionType::Sh => {
-				let (base, offset) = self.gen_base_and_offset(!0b1);
-				Box::new(SbInstr {
+				let (base, offset) = self.gen_base_and_offset(!0b1, None);
+				Box::new(ShInstr {
 					base,
 					rt: self.gen_grf_read_addr(None),
 					offset,
 				})
 			}
 			InstructionType::Sw => {
-				let (base, offset) = self.gen_base_and_offset(!0b11);
+				let allow_read_only = self.machine.exception_enabled();
+				let (base, offset) = self.gen_base_and_offset(!0b11, Some(allow_read_only));
 				Box::new(SwInstr {
 					base,
 					rt: self.gen_grf_read_addr(None),
 					offset,
 				})
 			}
+			InstructionType::Lwl => {
+				let (base, offset) = self.gen_base_and_offset(!0, None);
+				Box::new(LwlInstr {
+					base,
+					rt: self.gen_grf_merge_addr(),
+					offset,
+				})
+			}
+			InstructionType::Lwr => {
+				let (base, offset) = self.gen_base_and_offset(!0, None);
+				Box::new(LwrInstr {
+					base,
+					rt: self.gen_grf_merge_addr(),
+					offset,
+				})
+			}
+			InstructionType::Swl => {
+				let (base, offset) = self.gen_base_and_offset(!0, None);
+				Box::new(SwlInstr {
+					base,
+					rt: self.gen_grf_read_addr(None),
+					offset,
+				})
+			}
+			InstructionType::Swr => {
+				let (base, offset) = self.gen_base_and_offset(!0, None);
+				Box::new(SwrInstr {
+					base,
+					rt: self.gen_grf_read_addr(None),
+					offset,
+				})
+			}
+			InstructionType::Ll | InstructionType::Sc => unreachable!(),
 			InstructionType::Beq => Box::new(BeqInstr {
 				rs: self.gen_grf_read_addr(None),
 				rt: self.gen_grf_read_addr(None),
@@ -419,6 +1179,24 @@ impl Iterator for InstructionGenerator<'_> {
 				rs: self.gen_grf_read_addr(None),
 				offset: self.gen_branch_offset(),
 			}),
+			InstructionType::Bltzal => Box::new(BltzalInstr {
+				rs: self.gen_grf_read_addr(Some(GRF_RA)),
+				offset: self.gen_branch_offset(),
+			}),
+			InstructionType::Bgezal => Box::new(BgezalInstr {
+				rs: self.gen_grf_read_addr(Some(GRF_RA)),
+				offset: self.gen_branch_offset(),
+			}),
+			InstructionType::Beql => Box::new(BeqlInstr {
+				rs: self.gen_grf_read_addr(None),
+				rt: self.gen_grf_read_addr(None),
+				offset: self.gen_branch_offset(),
+			}),
+			InstructionType::Bnel => Box::new(BnelInstr {
+				rs: self.gen_grf_read_addr(None),
+				rt: self.gen_grf_read_addr(None),
+				offset: self.gen_branch_offset(),
+			}),
 			InstructionType::J => Box::new(JInstr {
 				addr: self.gen_jump_addr(),
 			}),
@@ -426,14 +1204,15 @@ impl Iterator for InstructionGenerator<'_> {
 				addr: self.gen_jump_addr(),
 			}),
 			InstructionType::Jr => Box::new(JrInstr {
-				rs: *self.rng.rand_select(&jr_candidates),
+				rs: self.forced_read.take().unwrap_or_else(|| *self.rng.rand_select(&jr_candidates)),
 			}),
 			InstructionType::Jalr => {
-				let rs = *self.rng.rand_select(&jr_candidates);
-				Box::new(JalrInstr {
-					rs,
-					rd: self.gen_grf_read_addr(Some(rs)),
-				})
+				let rs = self.forced_read.take().unwrap_or_else(|| *self.rng.rand_select(&jr_candidates));
+				let rd = loop {
+					let rd = self.gen_grf_read_addr(Some(rs));
+					if rd != GRF_SP || self.stack_top.is_none() { break rd; }
+				};
+				Box::new(JalrInstr { rs, rd })
 			},
 			InstructionType::Mult => Box::new(MultInstr {
 				rs: self.gen_grf_read_addr(None),
@@ -452,10 +1231,10 @@ impl Iterator for InstructionGenerator<'_> {
 				rt: self.gen_grf_read_addr(None),
 			}),
 			InstructionType::Mflo => Box::new(MfloInstr {
-				rd: self.rng.sample(&self.grf_addr_dist),
+				rd: self.gen_grf_write_addr(),
 			}),
 			InstructionType::Mfhi => Box::new(MfhiInstr {
-				rd: self.rng.sample(&self.grf_addr_dist),
+				rd: self.gen_grf_write_addr(),
 			}),
 			InstructionType::Mtlo => Box::new(MtloInstr {
 				rs: self.gen_grf_read_addr(None),
@@ -463,8 +1242,105 @@ impl Iterator for InstructionGenerator<'_> {
 			InstructionType::Mthi => Box::new(MthiInstr {
 				rs: self.gen_grf_read_addr(None),
 			}),
+			InstructionType::Movz => Box::new(MovzInstr {
+				rs: self.gen_grf_read_addr(None),
+				rt: self.gen_move_cond_addr(),
+				rd: self.gen_grf_write_addr(),
+			}),
+			InstructionType::Movn => Box::new(MovnInstr {
+				rs: self.gen_grf_read_addr(None),
+				rt: self.gen_move_cond_addr(),
+				rd: self.gen_grf_write_addr(),
+			}),
+			InstructionType::Clz => Box::new(ClzInstr {
+				rs: self.gen_grf_read_addr(None),
+				rd: self.gen_grf_write_addr(),
+			}),
+			InstructionType::Clo => Box::new(CloInstr {
+				rs: self.gen_grf_read_addr(None),
+				rd: self.gen_grf_write_addr(),
+			}),
+			InstructionType::Mul => Box::new(MulInstr {
+				rs: self.gen_grf_read_addr(None),
+				rt: self.gen_grf_read_addr(None),
+				rd: self.gen_grf_write_addr(),
+			}),
+			InstructionType::Madd => Box::new(MaddInstr {
+				rs: self.gen_grf_read_addr(None),
+				rt: self.gen_grf_read_addr(None),
+			}),
+			InstructionType::Maddu => Box::new(MadduInstr {
+				rs: self.gen_grf_read_addr(None),
+				rt: self.gen_grf_read_addr(None),
+			}),
+			InstructionType::Msub => Box::new(MsubInstr {
+				rs: self.gen_grf_read_addr(None),
+				rt: self.gen_grf_read_addr(None),
+			}),
+			InstructionType::Msubu => Box::new(MsubuInstr {
+				rs: self.gen_grf_read_addr(None),
+				rt: self.gen_grf_read_addr(None),
+			}),
+			InstructionType::Teq => {
+				let (rs, rt) = self.gen_trap_operands();
+				Box::new(TeqInstr { rs, rt })
+			}
+			InstructionType::Tne => {
+				let (rs, rt) = self.gen_trap_operands();
+				Box::new(TneInstr { rs, rt })
+			}
+			InstructionType::Tge => {
+				let (rs, rt) = self.gen_trap_operands();
+				Box::new(TgeInstr { rs, rt })
+			}
+			InstructionType::Tgeu => {
+				let (rs, rt) = self.gen_trap_operands();
+				Box::new(TgeuInstr { rs, rt })
+			}
+			InstructionType::Tlt => {
+				let (rs, rt) = self.gen_trap_operands();
+				Box::new(TltInstr { rs, rt })
+			}
+			InstructionType::Tltu => {
+				let (rs, rt) = self.gen_trap_operands();
+				Box::new(TltuInstr { rs, rt })
+			}
+			InstructionType::Teqi => {
+				let (rs, imm) = self.gen_trap_imm_operands();
+				Box::new(TeqiInstr { rs, imm })
+			}
+			InstructionType::Tnei => {
+				let (rs, imm) = self.gen_trap_imm_operands();
+				Box::new(TneiInstr { rs, imm })
+			}
+			InstructionType::Tgei => {
+				let (rs, imm) = self.gen_trap_imm_operands();
+				Box::new(TgeiInstr { rs, imm })
+			}
+			InstructionType::Tgeiu => {
+				let (rs, imm) = self.gen_trap_imm_operands();
+				Box::new(TgeiuInstr { rs, imm })
+			}
+			InstructionType::Tlti => {
+				let (rs, imm) = self.gen_trap_imm_operands();
+				Box::new(TltiInstr { rs, imm })
+			}
+			InstructionType::Tltiu => {
+				let (rs, imm) = self.gen_trap_imm_operands();
+				Box::new(TltiuInstr { rs, imm })
+			}
+			InstructionType::Syscall => Box::new(SyscallInstr {
+				code: self.gen_exc_code_field(),
+			}),
+			InstructionType::Break => Box::new(BreakInstr {
+				code: self.gen_exc_code_field(),
+			}),
+			InstructionType::Reserved => Box::new(ReservedInstr {
+				code: self.gen_reserved_code(),
+			}),
 		};
-		self.machine.execute(&*instr);
-		Some(instr)
+		self.forced_read = None;
+		self.forced_base = None;
+		Some(self.emit(instr))
 	}
 }
@@ -25,7 +25,6 @@ impl GrfLogEntry {
 
 	pub fn pc(&self) -> u32 { self.pc }
 	pub fn addr(&self) -> u8 { self.addr }
-	pub fn data(&self) -> u32 { self.data }
 }
 
 #[derive(Debug, Eq, PartialEq, Clone)]
@@ -46,6 +45,7 @@ impl MemLogEntry {
 		Self { pc, addr, data }
 	}
 
+	pub fn pc(&self) -> u32 { self.pc }
 	pub fn addr(&self) -> u32 { self.addr }
 }
 
@@ -1,19 +1,128 @@
 use std::collections::HashSet;
+use std::error::Error;
 use std::fmt::{self, Display, Formatter};
 use std::mem;
 
+use super::coverage::Coverage;
+use super::gen::InstructionType;
 use super::log::{GrfLogEntry, MemLogEntry};
+use super::timer::Timer;
 
 pub const WORD_SIZE: usize = mem::size_of::<u32>();
 pub const GRF_SIZE: usize = 32;
 pub const TEXT_START_ADDR: u32 = 0x3000;
-const HANDLER_ADDR: u32 = 0x4180;
+pub const TEXT_END_ADDR: u32 = 0x5000;
+pub const HANDLER_ADDR: u32 = 0x4180;
+pub const TIMER_ADDRS: [u32; 2] = [0x7f00, 0x7f10];
+// Data memory must end below the devices, or it would shadow them.
+pub const MAX_MEM_SIZE: usize = TIMER_ADDRS[0] as usize / WORD_SIZE;
+
+pub const CP0_STATUS: u8 = 12;
+pub const CP0_CAUSE: u8 = 13;
+pub const CP0_EPC: u8 = 14;
+pub const CP0_PRID: u8 = 15;
+
+const STATUS_IE: u32 = 1 << 0;
+const STATUS_EXL: u32 = 1 << 1;
+const STATUS_IM: u32 = 0b111111 << 10;
+const STATUS_RESET: u32 = 0b1000000000001;
+const CAUSE_BD: u32 = 1 << 31;
+const CAUSE_IP_SHIFT: u32 = 10;
+const CAUSE_EXC_CODE_SHIFT: u32 = 2;
+const PRID: u32 = 0xdeadbeaf;
+
+const HW_INT_TIMERS: [u8; 2] = [0, 1];
+const HW_INT_EXTERNAL: u8 = 2;
+// The testbench holds the external interrupt line for this many cycles.
+const EXTERNAL_IRQ_CYCLES: u32 = 10;
+pub const HANDLER_MAX_STEPS: usize = 1 << 16;
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum AccessSize {
+	Byte = 1,
+	Half = 2,
+	Word = 4,
+}
+
+impl AccessSize {
+	fn mask(self) -> u32 {
+		u32::MAX >> (32 - 8 * self as u32)
+	}
+}
+
+pub trait Device: Send {
+	fn size(&self) -> u32;
+	fn read(&self, offset: u32) -> u32;
+	fn write(&mut self, offset: u32, data: u32);
+	fn is_read_only(&self, _offset: u32) -> bool { false }
+	fn irq(&self) -> bool { false }
+	fn tick(&mut self) {}
+}
+
+struct MappedDevice {
+	base: u32,
+	hw_int: Option<u8>,
+	device: Box<dyn Device>,
+}
+
+// Mirrors bridge.v, devices only accept aligned word accesses.
+#[derive(Default)]
+pub struct Bus {
+	devices: Vec<MappedDevice>,
+}
+
+impl Bus {
+	pub fn attach(&mut self, base: u32, hw_int: Option<u8>, device: Box<dyn Device>) {
+		let end = base + device.size();
+		assert!(self.devices.iter().all(|x| end <= x.base || x.base + x.device.size() <= base),
+			"the device at 0x{:08x} overlaps another one", base);
+		self.devices.push(MappedDevice { base, hw_int, device });
+	}
+
+	fn find(&self, addr: u32) -> Option<usize> {
+		if addr & 0b11 != 0 { return None; }
+		self.devices.iter().position(|x| (x.base..x.base + x.device.size()).contains(&addr))
+	}
+
+	fn read(&self, addr: u32) -> Option<u32> {
+		self.find(addr).map(|id| {
+			let mapped = &self.devices[id];
+			mapped.device.read(addr - mapped.base)
+		})
+	}
+
+	fn write(&mut self, addr: u32, data: u32) -> bool {
+		if let Some(id) = self.find(addr) {
+			let mapped = &mut self.devices[id];
+			let offset = addr - mapped.base;
+			if !mapped.device.is_read_only(offset) {
+				mapped.device.write(offset, data);
+				return true;
+			}
+		}
+		false
+	}
+
+	fn tick(&mut self) {
+		for mapped in &mut self.devices {
+			mapped.device.tick();
+		}
+	}
+
+	fn hw_int(&self) -> u32 {
+		self.devices.iter()
+			.filter(|x| x.device.irq())
+			.filter_map(|x| x.hw_int)
+			.fold(0, |hw_int, line| hw_int | 1 << line)
+	}
+}
 
 #[derive(Debug, Eq, PartialEq, Copy, Clone)]
 pub enum MachineState {
 	Normal,
 	InDelaySlot(u32),
-	Branching(u32),
+	// The delay slot of an untaken likely branch, skipped without being executed.
+	AnnulledSlot,
 }
 
 pub struct MipsMachine {
@@ -21,31 +130,54 @@ pub struct MipsMachine {
 	exception_enabled: bool,
 	pc: u32,
 	state: MachineState,
+	next_pc: Option<u32>,
 	grf: Box<[u32; GRF_SIZE]>,
 	lo: u32,
 	hi: u32,
+	status: u32,
+	cause: u32,
+	epc: u32,
+	ll_bit: bool,
 	mem: Vec<u32>,
+	text: Vec<u32>,
+	handler: Vec<u32>,
+	bus: Bus,
 	grf_log: Vec<GrfLogEntry>,
 	mem_log: Vec<MemLogEntry>,
 	irq_log: HashSet<u32>,
-	exception_occurred: bool,
+	external_irq_cycles: u32,
+	coverage: Option<Coverage>,
 }
 
 impl MipsMachine {
-	pub fn new(delayed_branching: bool, exception_enabled: bool, mem_size: usize) -> Self {
+	pub fn new(delayed_branching: bool, exception_enabled: bool, mem_size: usize, handler: &[u32]) -> Self {
+		assert!(mem_size <= MAX_MEM_SIZE, "the data memory overlaps the devices");
+		let mut bus = Bus::default();
+		for (addr, hw_int) in TIMER_ADDRS.iter().zip(&HW_INT_TIMERS) {
+			bus.attach(*addr, Some(*hw_int), Box::<Timer>::default());
+		}
 		Self {
 			delayed_branching,
 			exception_enabled,
 			pc: TEXT_START_ADDR,
 			state: MachineState::Normal,
+			next_pc: None,
 			grf: Box::new([0u32; GRF_SIZE]),
 			lo: 0,
 			hi: 0,
+			status: STATUS_RESET,
+			cause: 0,
+			epc: 0,
+			ll_bit: false,
 			mem: vec![0u32; mem_size],
+			text: Vec::new(),
+			handler: handler.to_vec(),
+			bus,
 			grf_log: Vec::new(),
 			mem_log: Vec::new(),
 			irq_log: HashSet::new(),
-			exception_occurred: false,
+			external_irq_cycles: 0,
+			coverage: None,
 		}
 	}
 
@@ -56,7 +188,10 @@ impl MipsMachine {
 	pub fn grf_log(&self) -> &[GrfLogEntry] { &self.grf_log }
 	pub fn mem_log(&self) -> &[MemLogEntry] { &self.mem_log }
 	pub fn irq_log(&self) -> &HashSet<u32> { &self.irq_log }
+	pub fn coverage(&self) -> Option<&Coverage> { self.coverage.as_ref() }
+	pub fn delayed_branching(&self) -> bool { self.delayed_branching }
 	pub fn exception_enabled(&self) -> bool { self.exception_enabled }
+	pub fn text_end(&self) -> u32 { TEXT_START_ADDR + (self.text.len() * WORD_SIZE) as u32 }
 
 	fn get_word_addr(addr: u32) -> usize {
 		let addr = addr as usize;
@@ -65,6 +200,15 @@ impl MipsMachine {
 		word_addr
 	}
 
+	fn hi_lo(&self) -> u64 {
+		(self.hi as u64) << 32 | self.lo as u64
+	}
+
+	fn set_hi_lo(&mut self, data: u64) {
+		self.hi = (data >> 32) as u32;
+		self.lo = data as u32;
+	}
+
 	fn read_grf(&self, addr: u8) -> u32 {
 		self.grf[addr as usize]
 	}
@@ -85,153 +229,297 @@ impl MipsMachine {
 		self.mem_log.push(MemLogEntry::new(self.pc, addr, data));
 	}
 
-	fn handle_exception(&mut self, exc_code: u8, irq_no: Option<u8>) {
-		if !self.exception_enabled { return; }
-		if exc_code != 0 { self.exception_occurred = true; }
-		let old_pc = self.pc;
-		self.pc = HANDLER_ADDR;
-		self.write_mem(0, self.read_grf(1));
-		self.pc += WORD_SIZE as u32;
-		self.write_mem(WORD_SIZE as u32, self.read_grf(2));
-		self.pc += WORD_SIZE as u32;
-		let cause = if let Some(irq_no) = irq_no { 1 << (irq_no as u32 + 8) } else { 0 } | ((exc_code as u32) << 2);
-		if let MachineState::InDelaySlot(_) = self.state {
-			self.write_grf(1, (1 << 31) | cause);
-			self.pc += WORD_SIZE as u32;
-			self.write_grf(2, old_pc - WORD_SIZE as u32);
-			self.pc += WORD_SIZE as u32;
+	fn load(&self, addr: u32, size: AccessSize) -> Option<u32> {
+		if addr & (size as u32 - 1) != 0 {
+			None
+		} else if (addr as usize) >> 2 < self.mem.len() {
+			let shift = (addr & 0b11) * 8;
+			Some(self.read_mem(addr & !0b11) >> shift & size.mask())
+		} else if size == AccessSize::Word {
+			self.bus.read(addr)
 		} else {
-			self.write_grf(1, cause);
-			self.pc += WORD_SIZE as u32;
-			self.write_grf(2, old_pc);
-			self.pc += WORD_SIZE as u32;
+			None
 		}
-		self.write_mem(2 * WORD_SIZE as u32, self.read_grf(3));
-		self.pc += WORD_SIZE as u32;
-		self.write_grf(3, 0x1003);
-		self.pc += WORD_SIZE as u32;
-		self.write_mem(3 * WORD_SIZE as u32, self.read_grf(4));
-		self.pc += WORD_SIZE as u32;
-		self.write_grf(4, 0xdeadbeaf);
-		self.pc += WORD_SIZE as u32;
-		for i in 5u8..32 {
-			self.write_mem((i as u32 - 1) * WORD_SIZE as u32, self.read_grf(i));
-			self.pc += WORD_SIZE as u32;
+	}
+
+	fn can_store(&self, addr: u32, size: AccessSize) -> bool {
+		if addr & (size as u32 - 1) != 0 {
+			false
+		} else if (addr as usize) >> 2 < self.mem.len() {
+			true
+		} else {
+			size == AccessSize::Word && self.bus.find(addr).is_some_and(|id| {
+				let mapped = &self.bus.devices[id];
+				!mapped.device.is_read_only(addr - mapped.base)
+			})
 		}
-		self.write_grf(1, self.read_grf(1) & 124);
-		self.pc += WORD_SIZE as u32 * 3;
-		if self.read_grf(1) != 0 {
-			self.write_grf(1, self.lo);
-			self.pc += WORD_SIZE as u32;
-			self.write_mem(31 * WORD_SIZE as u32, self.lo);
-			self.pc += WORD_SIZE as u32;
-			self.write_grf(1, self.hi);
-			self.pc += WORD_SIZE as u32;
-			self.write_mem(32 * WORD_SIZE as u32, self.hi);
-			self.pc += WORD_SIZE as u32;
-			self.write_grf(2, self.read_grf(2) + 4);
-			self.pc += WORD_SIZE as u32 * 2;
-			self.write_grf(1, self.lo);
-			self.pc += WORD_SIZE as u32 * 2;
-			self.write_grf(1, self.hi);
-			self.pc += WORD_SIZE as u32 * 2;
+	}
+
+	fn store(&mut self, addr: u32, size: AccessSize, data: u32) -> bool {
+		if addr & (size as u32 - 1) != 0 {
+			false
+		} else if (addr as usize) >> 2 < self.mem.len() {
+			let shift = (addr & 0b11) * 8;
+			let mask = size.mask() << shift;
+			let word = self.read_mem(addr & !0b11) & !mask | data << shift & mask;
+			self.write_mem(addr & !0b11, word);
+			true
 		} else {
-			self.pc += WORD_SIZE as u32 * 10;
+			size == AccessSize::Word && self.bus.write(addr, data)
 		}
-		for i in 1u8..32 {
-			self.write_grf(i, self.read_mem(((i - 1) * 4) as u32));
-			self.pc += WORD_SIZE as u32;
+	}
+
+	// Partial-word accesses work on the aligned word around addr, devices don't support them.
+	fn load_partial(&self, addr: u32) -> Option<u32> {
+		if (addr as usize) >> 2 < self.mem.len() {
+			Some(self.read_mem(addr & !0b11))
+		} else {
+			None
+		}
+	}
+
+	fn store_partial(&mut self, addr: u32, mask: u32, data: u32) -> bool {
+		if (addr as usize) >> 2 < self.mem.len() {
+			let word = self.read_mem(addr & !0b11) & !mask | data & mask;
+			self.write_mem(addr & !0b11, word);
+			true
+		} else {
+			false
+		}
+	}
+
+	fn tick(&mut self) {
+		self.bus.tick();
+		self.external_irq_cycles = self.external_irq_cycles.saturating_sub(1);
+		self.update_ip();
+	}
+
+	// Cause.IP follows the interrupt lines, it isn't latched.
+	fn update_ip(&mut self) {
+		let external = if self.external_irq_cycles != 0 { 1 << HW_INT_EXTERNAL } else { 0 };
+		self.cause = self.cause & !(0b111111 << CAUSE_IP_SHIFT) | (self.bus.hw_int() | external) << CAUSE_IP_SHIFT;
+	}
+
+	fn int_req(&self) -> bool {
+		self.exception_enabled && self.status & (STATUS_EXL | STATUS_IE) == STATUS_IE && self.status & self.cause & STATUS_IM != 0
+	}
+
+	fn read_cp0(&self, addr: u8) -> u32 {
+		match addr {
+			CP0_STATUS => self.status,
+			CP0_CAUSE => self.cause,
+			CP0_EPC => self.epc,
+			CP0_PRID => PRID,
+			_ => 0,
+		}
+	}
+
+	fn write_cp0(&mut self, addr: u8, data: u32) {
+		match addr {
+			CP0_STATUS => self.status = data & (STATUS_IE | STATUS_EXL | STATUS_IM),
+			CP0_EPC => self.epc = data & !0b11,
+			_ => (),
+		}
+	}
+
+	fn handle_exception(&mut self, exc_code: u8) {
+		if !self.exception_enabled { return; }
+		if let Some(coverage) = &mut self.coverage {
+			coverage.record_exception(exc_code, (self.status & self.cause & STATUS_IM) >> CAUSE_IP_SHIFT);
+		}
+		let pc = self.pc & !0b11;
+		if let MachineState::InDelaySlot(_) = self.state {
+			self.epc = pc - WORD_SIZE as u32;
+			self.cause |= CAUSE_BD;
+		} else {
+			self.epc = pc;
+			self.cause &= !CAUSE_BD;
 		}
-		self.pc = old_pc;
+		self.cause = self.cause & !(0b11111 << CAUSE_EXC_CODE_SHIFT) | (exc_code as u32) << CAUSE_EXC_CODE_SHIFT;
+		self.status |= STATUS_EXL;
+		self.ll_bit = false;
+		self.next_pc = Some(HANDLER_ADDR);
 	}
 
-	fn check_branch_target(&mut self, target: u32) {
-		if target & 0b11 != 0 {
-			self.force_exception(target & !0b11, 4);
+	fn eret(&mut self) {
+		self.status &= !STATUS_EXL;
+		self.ll_bit = false;
+		self.next_pc = Some(self.epc);
+	}
+
+	fn fetch(&self, addr: u32) -> Option<u32> {
+		let text_id = u32::wrapping_sub(addr, TEXT_START_ADDR) as usize / WORD_SIZE;
+		let handler_id = u32::wrapping_sub(addr, HANDLER_ADDR) as usize / WORD_SIZE;
+		if addr >= TEXT_START_ADDR && text_id < self.text.len() {
+			Some(self.text[text_id])
+		} else if addr >= HANDLER_ADDR && handler_id < self.handler.len() {
+			Some(self.handler[handler_id])
+		} else {
+			None
 		}
 	}
 
-	pub fn execute<T: Instruction + ?Sized>(&mut self, instr: &T) {
+	fn execute<T: Instruction + ?Sized>(&mut self, instr: &T) {
+		let grf_log_len = self.grf_log.len();
+		let res = instr.execute_on(self);
+		let is_in_text = (TEXT_START_ADDR..self.text_end()).contains(&self.pc);
+		if let Some(coverage) = &mut self.coverage {
+			let is_in_delay_slot = matches!(self.state, MachineState::InDelaySlot(_));
+			let taken = match res {
+				BranchResult::None => None,
+				BranchResult::Yes(_) => Some(true),
+				BranchResult::No | BranchResult::Annul => Some(false),
+			};
+			let writes = &self.grf_log[grf_log_len..];
+			coverage.record(instr.instr_type(), instr.to_machine_code(), is_in_text, is_in_delay_slot, taken, writes);
+		}
+		self.tick();
+		if let Some(next_pc) = self.next_pc.take() {
+			self.pc = next_pc;
+			self.state = MachineState::Normal;
+			return;
+		}
 		match self.state {
-			MachineState::Normal => {
-				let res = instr.execute_on(self);
-				self.pc += WORD_SIZE as u32;
-				match res {
-					BranchResult::None => (),
-					BranchResult::No => {
-						self.state = if self.delayed_branching {
-							MachineState::InDelaySlot(self.pc + WORD_SIZE as u32)
-						} else {
-							MachineState::Normal
-						};
+			MachineState::Normal => match res {
+				BranchResult::None => self.pc += WORD_SIZE as u32,
+				BranchResult::No => {
+					if self.delayed_branching {
+						self.state = MachineState::InDelaySlot(self.pc + WORD_SIZE as u32 * 2);
 					}
-					BranchResult::Yes(target) => {
-						debug_assert!(target > self.pc - WORD_SIZE as u32);
-						if self.delayed_branching {
-							self.state = MachineState::InDelaySlot(target);
-						} else if target > self.pc {
-							self.state = MachineState::Branching(target);
-						} else {
-							self.check_branch_target(target);
-						}
+					self.pc += WORD_SIZE as u32;
+				}
+				BranchResult::Annul => {
+					if self.delayed_branching {
+						self.state = MachineState::AnnulledSlot;
 					}
+					self.pc += WORD_SIZE as u32;
 				}
-			}
-			MachineState::InDelaySlot(target) => {
-				debug_assert!(self.delayed_branching);
-				let res = instr.execute_on(self);
-				debug_assert_eq!(res, BranchResult::None);
-				if self.exception_occurred {
-					self.state = MachineState::Normal;
-					let res = instr.execute_on(self);
-					debug_assert_eq!(res, BranchResult::None);
-				} else if target <= self.pc + WORD_SIZE as u32 {
-					self.state = MachineState::Normal;
-					self.check_branch_target(target);
-					if target <= self.pc {
-						let res = instr.execute_on(self);
-						debug_assert_eq!(res, BranchResult::None);
+				BranchResult::Yes(target) => {
+					if self.delayed_branching {
+						self.state = MachineState::InDelaySlot(target);
+						self.pc += WORD_SIZE as u32;
+					} else {
+						self.pc = target;
 					}
-				} else {
-					self.state = MachineState::Branching(target)
 				}
-				self.pc += WORD_SIZE as u32;
+			},
+			// A branch in a delay slot is unpredictable, this model ignores it.
+			MachineState::InDelaySlot(target) => {
+				self.state = MachineState::Normal;
+				self.pc = target;
 			}
-			MachineState::Branching(target) => {
-				self.pc += WORD_SIZE as u32;
-				if target <= self.pc {
-					self.state = MachineState::Normal;
-					self.check_branch_target(target);
-				}
+			MachineState::AnnulledSlot => unreachable!(),
+		}
+	}
+
+	pub fn step(&mut self) {
+		if self.state == MachineState::AnnulledSlot {
+			// The annulled instruction still takes up a cycle.
+			if let Some(coverage) = &mut self.coverage {
+				coverage.record_annulled();
 			}
-		};
-		self.exception_occurred = false;
+			self.tick();
+			self.state = MachineState::Normal;
+			self.pc += WORD_SIZE as u32;
+			return;
+		}
+		if self.int_req() {
+			self.handle_exception(0);
+			self.pc = self.next_pc.take().unwrap();
+			self.state = MachineState::Normal;
+			return;
+		}
+		if self.pc & 0b11 != 0 || !(TEXT_START_ADDR..TEXT_END_ADDR).contains(&self.pc) {
+			if self.exception_enabled {
+				self.handle_exception(4);
+				self.pc = self.next_pc.take().unwrap();
+				self.state = MachineState::Normal;
+			} else {
+				self.pc = (self.pc & !0b11) + WORD_SIZE as u32;
+			}
+			return;
+		}
+		let code = self.fetch(self.pc).unwrap_or(0);
+		let instr = decode_instruction(code).unwrap_or_else(|_| Box::new(ReservedInstr { code }));
+		self.execute(&*instr);
+	}
+
+	pub fn enable_coverage(&mut self) {
+		self.coverage = Some(Coverage::default());
+	}
+
+	pub fn push_text(&mut self, code: u32) {
+		self.text.push(code);
+	}
+
+	pub fn run(&mut self, max_steps: usize) -> bool {
+		for _ in 0..max_steps {
+			if self.fetch(self.pc & !0b11).is_none() {
+				return true;
+			}
+			self.step();
+		}
+		false
+	}
+
+	// Raises the external interrupt the first time each address in `irqs` is reached, as the testbench does,
+	// and removes that address from the set. Stops without finishing at a branch in a delay slot, which has
+	// no defined behavior.
+	pub fn run_with_irqs(&mut self, irqs: &mut HashSet<u32>, max_steps: usize) -> bool {
+		for _ in 0..max_steps {
+			if self.fetch(self.pc & !0b11).is_none() {
+				return true;
+			}
+			if self.is_at_branch_in_delay_slot() {
+				return false;
+			}
+			if irqs.remove(&self.pc) {
+				self.interrupt();
+			}
+			self.step();
+		}
+		false
+	}
+
+	pub fn is_at_branch_in_delay_slot(&self) -> bool {
+		matches!(self.state, MachineState::InDelaySlot(_)) && self.pc & 0b11 == 0 &&
+			self.fetch(self.pc).is_some_and(has_delay_slot)
 	}
 
 	pub fn force_exception(&mut self, pc: u32, exc_code: u8) {
-		let old_pc = self.pc;
 		self.pc = pc;
-		self.handle_exception(exc_code, None);
-		self.pc = old_pc;
+		self.state = MachineState::Normal;
+		self.handle_exception(exc_code);
+		if let Some(next_pc) = self.next_pc.take() {
+			self.pc = next_pc;
+			self.run(HANDLER_MAX_STEPS);
+		}
 	}
 
 	pub fn interrupt(&mut self) {
-		if !matches!(self.state, MachineState::Branching(_)) {
-			let last_grf_log = self.grf_log.last().cloned();
+		let ip = 1 << (HW_INT_EXTERNAL as u32 + CAUSE_IP_SHIFT);
+		// An annulled slot is never issued, so there is no instruction to interrupt.
+		let is_issued = self.state != MachineState::AnnulledSlot;
+		if self.exception_enabled && is_issued && self.status & (STATUS_EXL | STATUS_IE) == STATUS_IE && self.status & ip != 0 {
 			self.irq_log.insert(self.pc);
-			self.handle_exception(0, Some(4));
-			if let (MachineState::InDelaySlot(_), Some(last_grf_log)) = (self.state, last_grf_log) {
-				if last_grf_log.pc() == self.pc - WORD_SIZE as u32 {
-					let old_pc = self.pc;
-					self.pc = last_grf_log.pc();
-					self.write_grf(last_grf_log.addr(), last_grf_log.data());
-					self.pc = old_pc;
-				}
-			}
+			self.external_irq_cycles = EXTERNAL_IRQ_CYCLES;
+			self.update_ip();
+			self.handle_exception(0);
+			self.pc = self.next_pc.take().unwrap();
+			self.state = MachineState::Normal;
 		}
 	}
 }
 
+pub fn has_delay_slot(code: u32) -> bool {
+	match code >> 26 {
+		0b000000 => matches!(code & 0x3f, 0b001000 | 0b001001),
+		0b000001 => matches!(code >> 16 & 0x1f, 0b00000 | 0b00001 | 0b10000 | 0b10001),
+		0b000010..=0b000111 | 0b010100 | 0b010101 => true,
+		_ => false,
+	}
+}
+
 fn gen_machine_code_r(op: u8, rs: u8, rt: u8, rd: u8, shamt: u8, func: u8) -> u32 {
 	debug_assert!(op < 64);
 	debug_assert!(rs < 32);
@@ -259,10 +547,13 @@ fn gen_machine_code_j(op: u8, addr: u32) -> u32 {
 pub enum BranchResult {
 	None,
 	No,
+	Annul,
 	Yes(u32),
 }
 
 pub trait Instruction: Display {
+	// None for the CP0 instructions, which are never generated.
+	fn instr_type(&self) -> Option<InstructionType>;
 	fn to_machine_code(&self) -> u32;
 	fn execute_on(&self, machine: &mut MipsMachine) -> BranchResult;
 }
@@ -277,6 +568,7 @@ impl Display for NopInstr {
 }
 
 impl Instruction for NopInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Nop) }
 	fn to_machine_code(&self) -> u32 { 0 }
 	fn execute_on(&self, _machine: &mut MipsMachine) -> BranchResult { BranchResult::None }
 }
@@ -295,6 +587,7 @@ impl Display for AddInstr {
 }
 
 impl Instruction for AddInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Add) }
 	fn to_machine_code(&self) -> u32 {
 		gen_machine_code_r(0, self.rs, self.rt, self.rd, 0, 0b100000)
 	}
@@ -303,7 +596,7 @@ impl Instruction for AddInstr {
 		if let Some(res) = i32::checked_add(machine.read_grf(self.rs) as i32, machine.read_grf(self.rt) as i32) {
 			machine.write_grf(self.rd, res as u32);
 		} else {
-			machine.handle_exception(12, None);
+			machine.handle_exception(12);
 		}
 		BranchResult::None
 	}
@@ -323,6 +616,7 @@ impl Display for AddiInstr {
 }
 
 impl Instruction for AddiInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Addi) }
 	fn to_machine_code(&self) -> u32 {
 		gen_machine_code_i(0b001000, self.rs, self.rt, self.imm as u16)
 	}
@@ -331,7 +625,7 @@ impl Instruction for AddiInstr {
 		if let Some(res) = i32::checked_add(machine.read_grf(self.rs) as i32, self.imm as i32) {
 			machine.write_grf(self.rt, res as u32);
 		} else {
-			machine.handle_exception(12, None);
+			machine.handle_exception(12);
 		}
 		BranchResult::None
 	}
@@ -351,6 +645,7 @@ impl Display for AdduInstr {
 }
 
 impl Instruction for AdduInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Addu) }
 	fn to_machine_code(&self) -> u32 {
 		gen_machine_code_r(0, self.rs, self.rt, self.rd, 0, 0b100001)
 	}
@@ -378,6 +673,7 @@ impl Display for AddiuInstr {
 }
 
 impl Instruction for AddiuInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Addiu) }
 	fn to_machine_code(&self) -> u32 {
 		gen_machine_code_i(0b001001, self.rs, self.rt, self.imm as u16)
 	}
@@ -405,6 +701,7 @@ impl Display for SubInstr {
 }
 
 impl Instruction for SubInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Sub) }
 	fn to_machine_code(&self) -> u32 {
 		gen_machine_code_r(0, self.rs, self.rt, self.rd, 0, 0b100010)
 	}
@@ -413,7 +710,7 @@ impl Instruction for SubInstr {
 		if let Some(res) = i32::checked_sub(machine.read_grf(self.rs) as i32, machine.read_grf(self.rt) as i32) {
 			machine.write_grf(self.rd, res as u32);
 		} else {
-			machine.handle_exception(12, None);
+			machine.handle_exception(12);
 		}
 		BranchResult::None
 	}
@@ -433,6 +730,7 @@ impl Display for SubuInstr {
 }
 
 impl Instruction for SubuInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Subu) }
 	fn to_machine_code(&self) -> u32 {
 		gen_machine_code_r(0, self.rs, self.rt, self.rd, 0, 0b100011)
 	}
@@ -460,6 +758,7 @@ impl Display for SllInstr {
 }
 
 impl Instruction for SllInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Sll) }
 	fn to_machine_code(&self) -> u32 {
 		gen_machine_code_r(0, 0, self.rt, self.rd, self.sa, 0b000000)
 	}
@@ -484,6 +783,7 @@ impl Display for SllvInstr {
 }
 
 impl Instruction for SllvInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Sllv) }
 	fn to_machine_code(&self) -> u32 {
 		gen_machine_code_r(0, self.rs, self.rt, self.rd, 0, 0b000100)
 	}
@@ -508,6 +808,7 @@ impl Display for SrlInstr {
 }
 
 impl Instruction for SrlInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Srl) }
 	fn to_machine_code(&self) -> u32 {
 		gen_machine_code_r(0, 0, self.rt, self.rd, self.sa, 0b000010)
 	}
@@ -532,6 +833,7 @@ impl Display for SrlvInstr {
 }
 
 impl Instruction for SrlvInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Srlv) }
 	fn to_machine_code(&self) -> u32 {
 		gen_machine_code_r(0, self.rs, self.rt, self.rd, 0, 0b000110)
 	}
@@ -556,6 +858,7 @@ impl Display for SraInstr {
 }
 
 impl Instruction for SraInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Sra) }
 	fn to_machine_code(&self) -> u32 {
 		gen_machine_code_r(0, 0, self.rt, self.rd, self.sa, 0b000011)
 	}
@@ -580,6 +883,7 @@ impl Display for SravInstr {
 }
 
 impl Instruction for SravInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Srav) }
 	fn to_machine_code(&self) -> u32 {
 		gen_machine_code_r(0, self.rs, self.rt, self.rd, 0, 0b000111)
 	}
@@ -607,6 +911,7 @@ impl Display for SltInstr {
 }
 
 impl Instruction for SltInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Slt) }
 	fn to_machine_code(&self) -> u32 {
 		gen_machine_code_r(0, self.rs, self.rt, self.rd, 0, 0b101010)
 	}
@@ -634,6 +939,7 @@ impl Display for SltiInstr {
 }
 
 impl Instruction for SltiInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Slti) }
 	fn to_machine_code(&self) -> u32 {
 		gen_machine_code_i(0b001010, self.rs, self.rt, self.imm as u16)
 	}
@@ -661,6 +967,7 @@ impl Display for SltuInstr {
 }
 
 impl Instruction for SltuInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Sltu) }
 	fn to_machine_code(&self) -> u32 {
 		gen_machine_code_r(0, self.rs, self.rt, self.rd, 0, 0b101011)
 	}
@@ -688,6 +995,7 @@ impl Display for SltiuInstr {
 }
 
 impl Instruction for SltiuInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Sltiu) }
 	fn to_machine_code(&self) -> u32 {
 		gen_machine_code_i(0b001011, self.rs, self.rt, self.imm as u16)
 	}
@@ -715,6 +1023,7 @@ impl Display for AndInstr {
 }
 
 impl Instruction for AndInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::And) }
 	fn to_machine_code(&self) -> u32 {
 		gen_machine_code_r(0, self.rs, self.rt, self.rd, 0, 0b100100)
 	}
@@ -742,6 +1051,7 @@ impl Display for AndiInstr {
 }
 
 impl Instruction for AndiInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Andi) }
 	fn to_machine_code(&self) -> u32 {
 		gen_machine_code_i(0b001100, self.rs, self.rt, self.imm)
 	}
@@ -769,6 +1079,7 @@ impl Display for OrInstr {
 }
 
 impl Instruction for OrInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Or) }
 	fn to_machine_code(&self) -> u32 {
 		gen_machine_code_r(0, self.rs, self.rt, self.rd, 0, 0b100101)
 	}
@@ -796,6 +1107,7 @@ impl Display for OriInstr {
 }
 
 impl Instruction for OriInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Ori) }
 	fn to_machine_code(&self) -> u32 {
 		gen_machine_code_i(0b001101, self.rs, self.rt, self.imm)
 	}
@@ -823,6 +1135,7 @@ impl Display for XorInstr {
 }
 
 impl Instruction for XorInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Xor) }
 	fn to_machine_code(&self) -> u32 {
 		gen_machine_code_r(0, self.rs, self.rt, self.rd, 0, 0b100110)
 	}
@@ -850,6 +1163,7 @@ impl Display for XoriInstr {
 }
 
 impl Instruction for XoriInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Xori) }
 	fn to_machine_code(&self) -> u32 {
 		gen_machine_code_i(0b001110, self.rs, self.rt, self.imm)
 	}
@@ -877,6 +1191,7 @@ impl Display for NorInstr {
 }
 
 impl Instruction for NorInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Nor) }
 	fn to_machine_code(&self) -> u32 {
 		gen_machine_code_r(0, self.rs, self.rt, self.rd, 0, 0b100111)
 	}
@@ -903,6 +1218,7 @@ impl Display for LuiInstr {
 }
 
 impl Instruction for LuiInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Lui) }
 	fn to_machine_code(&self) -> u32 {
 		gen_machine_code_i(0b001111, 0, self.rt, self.imm)
 	}
@@ -927,17 +1243,16 @@ impl Display for LbInstr {
 }
 
 impl Instruction for LbInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Lb) }
 	fn to_machine_code(&self) -> u32 {
 		gen_machine_code_i(0b100000, self.base, self.rt, self.offset as u16)
 	}
 
 	fn execute_on(&self, machine: &mut MipsMachine) -> BranchResult {
 		let addr = u32::wrapping_add(machine.read_grf(self.base), self.offset as u32);
-		if (addr as usize) >> 2 < machine.mem.len() {
-			let mem_bytes = machine.read_mem(addr & !0b11).to_le_bytes();
-			machine.write_grf(self.rt, mem_bytes[(addr & 0b11) as usize] as i8 as u32);
-		} else {
-			machine.handle_exception(4, None);
+		match machine.load(addr, AccessSize::Byte) {
+			Some(data) => machine.write_grf(self.rt, data as i8 as u32),
+			None => machine.handle_exception(4),
 		}
 		BranchResult::None
 	}
@@ -957,17 +1272,16 @@ impl Display for LbuInstr {
 }
 
 impl Instruction for LbuInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Lbu) }
 	fn to_machine_code(&self) -> u32 {
 		gen_machine_code_i(0b100100, self.base, self.rt, self.offset as u16)
 	}
 
 	fn execute_on(&self, machine: &mut MipsMachine) -> BranchResult {
 		let addr = u32::wrapping_add(machine.read_grf(self.base), self.offset as u32);
-		if (addr as usize) >> 2 < machine.mem.len() {
-			let mem_bytes = machine.read_mem(addr & !0b11).to_le_bytes();
-			machine.write_grf(self.rt, mem_bytes[(addr & 0b11) as usize] as u32);
-		} else {
-			machine.handle_exception(4, None);
+		match machine.load(addr, AccessSize::Byte) {
+			Some(data) => machine.write_grf(self.rt, data),
+			None => machine.handle_exception(4),
 		}
 		BranchResult::None
 	}
@@ -987,19 +1301,16 @@ impl Display for LhInstr {
 }
 
 impl Instruction for LhInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Lh) }
 	fn to_machine_code(&self) -> u32 {
 		gen_machine_code_i(0b100001, self.base, self.rt, self.offset as u16)
 	}
 
 	fn execute_on(&self, machine: &mut MipsMachine) -> BranchResult {
 		let addr = u32::wrapping_add(machine.read_grf(self.base), self.offset as u32);
-		if addr & 0b1 == 0 && (addr as usize) >> 2 < machine.mem.len() {
-			let mem_bytes = machine.read_mem(addr & !0b11).to_le_bytes();
-			let byte_offset = (addr & 0b10) as usize;
-			let data_bytes = [mem_bytes[byte_offset], mem_bytes[byte_offset + 1]];
-			machine.write_grf(self.rt, i16::from_le_bytes(data_bytes) as u32);
-		} else {
-			machine.handle_exception(4, None);
+		match machine.load(addr, AccessSize::Half) {
+			Some(data) => machine.write_grf(self.rt, data as i16 as u32),
+			None => machine.handle_exception(4),
 		}
 		BranchResult::None
 	}
@@ -1019,19 +1330,16 @@ impl Display for LhuInstr {
 }
 
 impl Instruction for LhuInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Lhu) }
 	fn to_machine_code(&self) -> u32 {
 		gen_machine_code_i(0b100101, self.base, self.rt, self.offset as u16)
 	}
 
 	fn execute_on(&self, machine: &mut MipsMachine) -> BranchResult {
 		let addr = u32::wrapping_add(machine.read_grf(self.base), self.offset as u32);
-		if addr & 0b1 == 0 && (addr as usize) >> 2 < machine.mem.len() {
-			let mem_bytes = machine.read_mem(addr & !0b11).to_le_bytes();
-			let byte_offset = (addr & 0b10) as usize;
-			let data_bytes = [mem_bytes[byte_offset], mem_bytes[byte_offset + 1]];
-			machine.write_grf(self.rt, u16::from_le_bytes(data_bytes) as u32);
-		} else {
-			machine.handle_exception(4, None);
+		match machine.load(addr, AccessSize::Half) {
+			Some(data) => machine.write_grf(self.rt, data),
+			None => machine.handle_exception(4),
 		}
 		BranchResult::None
 	}
@@ -1051,16 +1359,16 @@ impl Display for LwInstr {
 }
 
 impl Instruction for LwInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Lw) }
 	fn to_machine_code(&self) -> u32 {
 		gen_machine_code_i(0b100011, self.base, self.rt, self.offset as u16)
 	}
 
 	fn execute_on(&self, machine: &mut MipsMachine) -> BranchResult {
 		let addr = u32::wrapping_add(machine.read_grf(self.base), self.offset as u32);
-		if addr & 0b11 == 0 && (addr as usize) >> 2 < machine.mem.len() {
-			machine.write_grf(self.rt, machine.read_mem(addr));
-		} else {
-			machine.handle_exception(4, None);
+		match machine.load(addr, AccessSize::Word) {
+			Some(data) => machine.write_grf(self.rt, data),
+			None => machine.handle_exception(4),
 		}
 		BranchResult::None
 	}
@@ -1080,18 +1388,15 @@ impl Display for SbInstr {
 }
 
 impl Instruction for SbInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Sb) }
 	fn to_machine_code(&self) -> u32 {
 		gen_machine_code_i(0b101000, self.base, self.rt, self.offset as u16)
 	}
 
 	fn execute_on(&self, machine: &mut MipsMachine) -> BranchResult {
 		let addr = u32::wrapping_add(machine.read_grf(self.base), self.offset as u32);
-		if (addr as usize) >> 2 < machine.mem.len() {
-			let mut mem_bytes = machine.read_mem(addr & !0b11).to_le_bytes();
-			mem_bytes[(addr & 0b11) as usize] = machine.read_grf(self.rt) as u8;
-			machine.write_mem(addr & !0b11, u32::from_le_bytes(mem_bytes));
-		} else {
-			machine.handle_exception(5, None);
+		if !machine.store(addr, AccessSize::Byte, machine.read_grf(self.rt)) {
+			machine.handle_exception(5);
 		}
 		BranchResult::None
 	}
@@ -1111,21 +1416,15 @@ impl Display for ShInstr {
 }
 
 impl Instruction for ShInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Sh) }
 	fn to_machine_code(&self) -> u32 {
 		gen_machine_code_i(0b101001, self.base, self.rt, self.offset as u16)
 	}
 
 	fn execute_on(&self, machine: &mut MipsMachine) -> BranchResult {
 		let addr = u32::wrapping_add(machine.read_grf(self.base), self.offset as u32);
-		if addr & 0b1 == 0 && (addr as usize) >> 2 < machine.mem.len() {
-			let mut mem_bytes = machine.read_mem(addr & !0b11).to_le_bytes();
-			let data_bytes = machine.read_grf(self.rt).to_le_bytes();
-			let byte_offset = (addr & 0b10) as usize;
-			mem_bytes[byte_offset] = data_bytes[0];
-			mem_bytes[byte_offset + 1] = data_bytes[1];
-			machine.write_mem(addr & !0b11, u32::from_le_bytes(mem_bytes));
-		} else {
-			machine.handle_exception(5, None);
+		if !machine.store(addr, AccessSize::Half, machine.read_grf(self.rt)) {
+			machine.handle_exception(5);
 		}
 		BranchResult::None
 	}
@@ -1145,16 +1444,15 @@ impl Display for SwInstr {
 }
 
 impl Instruction for SwInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Sw) }
 	fn to_machine_code(&self) -> u32 {
 		gen_machine_code_i(0b101011, self.base, self.rt, self.offset as u16)
 	}
 
 	fn execute_on(&self, machine: &mut MipsMachine) -> BranchResult {
 		let addr = u32::wrapping_add(machine.read_grf(self.base), self.offset as u32);
-		if addr & 0b11 == 0 && (addr as usize) >> 2 < machine.mem.len() {
-			machine.write_mem(addr, machine.read_grf(self.rt));
-		} else {
-			machine.handle_exception(5, None);
+		if !machine.store(addr, AccessSize::Word, machine.read_grf(self.rt)) {
+			machine.handle_exception(5);
 		}
 		BranchResult::None
 	}
@@ -1168,6 +1466,196 @@ fn calc_branch_addr(pc: u32, offset: i16) -> u32 {
 	u32::wrapping_add(addr, WORD_SIZE as u32)
 }
 
+#[derive(Debug, Copy, Clone)]
+pub struct LlInstr {
+	pub base: u8,
+	pub rt: u8,
+	pub offset: i16,
+}
+
+impl Display for LlInstr {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		write!(f, "ll ${}, {}(${})", self.rt, self.offset, self.base)
+	}
+}
+
+impl Instruction for LlInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Ll) }
+	fn to_machine_code(&self) -> u32 {
+		gen_machine_code_i(0b110000, self.base, self.rt, self.offset as u16)
+	}
+
+	fn execute_on(&self, machine: &mut MipsMachine) -> BranchResult {
+		let addr = u32::wrapping_add(machine.read_grf(self.base), self.offset as u32);
+		match machine.load(addr, AccessSize::Word) {
+			Some(data) => {
+				machine.write_grf(self.rt, data);
+				machine.ll_bit = true;
+			}
+			None => machine.handle_exception(4),
+		}
+		BranchResult::None
+	}
+}
+
+// sc consumes the link, so only the first sc after an ll can succeed.
+#[derive(Debug, Copy, Clone)]
+pub struct ScInstr {
+	pub base: u8,
+	pub rt: u8,
+	pub offset: i16,
+}
+
+impl Display for ScInstr {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		write!(f, "sc ${}, {}(${})", self.rt, self.offset, self.base)
+	}
+}
+
+impl Instruction for ScInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Sc) }
+	fn to_machine_code(&self) -> u32 {
+		gen_machine_code_i(0b111000, self.base, self.rt, self.offset as u16)
+	}
+
+	fn execute_on(&self, machine: &mut MipsMachine) -> BranchResult {
+		let addr = u32::wrapping_add(machine.read_grf(self.base), self.offset as u32);
+		if !machine.can_store(addr, AccessSize::Word) {
+			machine.handle_exception(5);
+		} else if mem::take(&mut machine.ll_bit) {
+			machine.store(addr, AccessSize::Word, machine.read_grf(self.rt));
+			machine.write_grf(self.rt, 1);
+		} else {
+			machine.write_grf(self.rt, 0);
+		}
+		BranchResult::None
+	}
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct LwlInstr {
+	pub base: u8,
+	pub rt: u8,
+	pub offset: i16,
+}
+
+impl Display for LwlInstr {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		write!(f, "lwl ${}, {}(${})", self.rt, self.offset, self.base)
+	}
+}
+
+impl Instruction for LwlInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Lwl) }
+	fn to_machine_code(&self) -> u32 {
+		gen_machine_code_i(0b100010, self.base, self.rt, self.offset as u16)
+	}
+
+	fn execute_on(&self, machine: &mut MipsMachine) -> BranchResult {
+		let addr = u32::wrapping_add(machine.read_grf(self.base), self.offset as u32);
+		match machine.load_partial(addr) {
+			Some(word) => {
+				// The bytes from addr down to the word boundary fill rt from the top.
+				let shift = (3 - (addr & 0b11)) * 8;
+				machine.write_grf(self.rt, word << shift | machine.read_grf(self.rt) & !(u32::MAX << shift));
+			}
+			None => machine.handle_exception(4),
+		}
+		BranchResult::None
+	}
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct LwrInstr {
+	pub base: u8,
+	pub rt: u8,
+	pub offset: i16,
+}
+
+impl Display for LwrInstr {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		write!(f, "lwr ${}, {}(${})", self.rt, self.offset, self.base)
+	}
+}
+
+impl Instruction for LwrInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Lwr) }
+	fn to_machine_code(&self) -> u32 {
+		gen_machine_code_i(0b100110, self.base, self.rt, self.offset as u16)
+	}
+
+	fn execute_on(&self, machine: &mut MipsMachine) -> BranchResult {
+		let addr = u32::wrapping_add(machine.read_grf(self.base), self.offset as u32);
+		match machine.load_partial(addr) {
+			Some(word) => {
+				// The bytes from addr up to the word boundary fill rt from the bottom.
+				let shift = (addr & 0b11) * 8;
+				machine.write_grf(self.rt, word >> shift | machine.read_grf(self.rt) & !(u32::MAX >> shift));
+			}
+			None => machine.handle_exception(4),
+		}
+		BranchResult::None
+	}
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct SwlInstr {
+	pub base: u8,
+	pub rt: u8,
+	pub offset: i16,
+}
+
+impl Display for SwlInstr {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		write!(f, "swl ${}, {}(${})", self.rt, self.offset, self.base)
+	}
+}
+
+impl Instruction for SwlInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Swl) }
+	fn to_machine_code(&self) -> u32 {
+		gen_machine_code_i(0b101010, self.base, self.rt, self.offset as u16)
+	}
+
+	fn execute_on(&self, machine: &mut MipsMachine) -> BranchResult {
+		let addr = u32::wrapping_add(machine.read_grf(self.base), self.offset as u32);
+		let shift = (3 - (addr & 0b11)) * 8;
+		if !machine.store_partial(addr, u32::MAX >> shift, machine.read_grf(self.rt) >> shift) {
+			machine.handle_exception(5);
+		}
+		BranchResult::None
+	}
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct SwrInstr {
+	pub base: u8,
+	pub rt: u8,
+	pub offset: i16,
+}
+
+impl Display for SwrInstr {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		write!(f, "swr ${}, {}(${})", self.rt, self.offset, self.base)
+	}
+}
+
+impl Instruction for SwrInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Swr) }
+	fn to_machine_code(&self) -> u32 {
+		gen_machine_code_i(0b101110, self.base, self.rt, self.offset as u16)
+	}
+
+	fn execute_on(&self, machine: &mut MipsMachine) -> BranchResult {
+		let addr = u32::wrapping_add(machine.read_grf(self.base), self.offset as u32);
+		let shift = (addr & 0b11) * 8;
+		if !machine.store_partial(addr, u32::MAX << shift, machine.read_grf(self.rt) << shift) {
+			machine.handle_exception(5);
+		}
+		BranchResult::None
+	}
+}
+
 #[derive(Debug, Copy, Clone)]
 pub struct BeqInstr {
 	pub rs: u8,
@@ -1182,6 +1670,7 @@ impl Display for BeqInstr {
 }
 
 impl Instruction for BeqInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Beq) }
 	fn to_machine_code(&self) -> u32 {
 		gen_machine_code_i(0b000100, self.rs, self.rt, self.offset as u16)
 	}
@@ -1209,6 +1698,7 @@ impl Display for BneInstr {
 }
 
 impl Instruction for BneInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Bne) }
 	fn to_machine_code(&self) -> u32 {
 		gen_machine_code_i(0b000101, self.rs, self.rt, self.offset as u16)
 	}
@@ -1235,6 +1725,7 @@ impl Display for BlezInstr {
 }
 
 impl Instruction for BlezInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Blez) }
 	fn to_machine_code(&self) -> u32 {
 		gen_machine_code_i(0b000110, self.rs, 0, self.offset as u16)
 	}
@@ -1261,6 +1752,7 @@ impl Display for BltzInstr {
 }
 
 impl Instruction for BltzInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Bltz) }
 	fn to_machine_code(&self) -> u32 {
 		gen_machine_code_i(0b000001, self.rs, 0b00000, self.offset as u16)
 	}
@@ -1287,6 +1779,7 @@ impl Display for BgezInstr {
 }
 
 impl Instruction for BgezInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Bgez) }
 	fn to_machine_code(&self) -> u32 {
 		gen_machine_code_i(0b000001, self.rs, 0b00001, self.offset as u16)
 	}
@@ -1301,24 +1794,27 @@ impl Instruction for BgezInstr {
 }
 
 #[derive(Debug, Copy, Clone)]
-pub struct BgtzInstr {
+pub struct BltzalInstr {
 	pub rs: u8,
 	pub offset: i16,
 }
 
-impl Display for BgtzInstr {
+impl Display for BltzalInstr {
 	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-		write!(f, "bgtz ${}, {}", self.rs, self.offset)
+		write!(f, "bltzal ${}, {}", self.rs, self.offset)
 	}
 }
 
-impl Instruction for BgtzInstr {
+impl Instruction for BltzalInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Bltzal) }
 	fn to_machine_code(&self) -> u32 {
-		gen_machine_code_i(0b000111, self.rs, 0, self.offset as u16)
+		gen_machine_code_i(0b000001, self.rs, 0b10000, self.offset as u16)
 	}
 
 	fn execute_on(&self, machine: &mut MipsMachine) -> BranchResult {
-		if machine.read_grf(self.rs) as i32 > 0 {
+		let taken = (machine.read_grf(self.rs) as i32) < 0;
+		machine.write_grf(31, machine.pc() + WORD_SIZE as u32 * 2);
+		if taken {
 			BranchResult::Yes(calc_branch_addr(machine.pc(), self.offset))
 		} else {
 			BranchResult::No
@@ -1326,7 +1822,119 @@ impl Instruction for BgtzInstr {
 	}
 }
 
-const PC_MUSK: u32 = u32::max_value() >> 28 << 28;
+#[derive(Debug, Copy, Clone)]
+pub struct BgezalInstr {
+	pub rs: u8,
+	pub offset: i16,
+}
+
+impl Display for BgezalInstr {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		write!(f, "bgezal ${}, {}", self.rs, self.offset)
+	}
+}
+
+impl Instruction for BgezalInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Bgezal) }
+	fn to_machine_code(&self) -> u32 {
+		gen_machine_code_i(0b000001, self.rs, 0b10001, self.offset as u16)
+	}
+
+	fn execute_on(&self, machine: &mut MipsMachine) -> BranchResult {
+		let taken = (machine.read_grf(self.rs) as i32) >= 0;
+		machine.write_grf(31, machine.pc() + WORD_SIZE as u32 * 2);
+		if taken {
+			BranchResult::Yes(calc_branch_addr(machine.pc(), self.offset))
+		} else {
+			BranchResult::No
+		}
+	}
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct BeqlInstr {
+	pub rs: u8,
+	pub rt: u8,
+	pub offset: i16,
+}
+
+impl Display for BeqlInstr {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		write!(f, "beql ${}, ${}, {}", self.rs, self.rt, self.offset)
+	}
+}
+
+impl Instruction for BeqlInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Beql) }
+	fn to_machine_code(&self) -> u32 {
+		gen_machine_code_i(0b010100, self.rs, self.rt, self.offset as u16)
+	}
+
+	fn execute_on(&self, machine: &mut MipsMachine) -> BranchResult {
+		if machine.read_grf(self.rs) == machine.read_grf(self.rt) {
+			BranchResult::Yes(calc_branch_addr(machine.pc(), self.offset))
+		} else {
+			BranchResult::Annul
+		}
+	}
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct BnelInstr {
+	pub rs: u8,
+	pub rt: u8,
+	pub offset: i16,
+}
+
+impl Display for BnelInstr {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		write!(f, "bnel ${}, ${}, {}", self.rs, self.rt, self.offset)
+	}
+}
+
+impl Instruction for BnelInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Bnel) }
+	fn to_machine_code(&self) -> u32 {
+		gen_machine_code_i(0b010101, self.rs, self.rt, self.offset as u16)
+	}
+
+	fn execute_on(&self, machine: &mut MipsMachine) -> BranchResult {
+		if machine.read_grf(self.rs) != machine.read_grf(self.rt) {
+			BranchResult::Yes(calc_branch_addr(machine.pc(), self.offset))
+		} else {
+			BranchResult::Annul
+		}
+	}
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct BgtzInstr {
+	pub rs: u8,
+	pub offset: i16,
+}
+
+impl Display for BgtzInstr {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		write!(f, "bgtz ${}, {}", self.rs, self.offset)
+	}
+}
+
+impl Instruction for BgtzInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Bgtz) }
+	fn to_machine_code(&self) -> u32 {
+		gen_machine_code_i(0b000111, self.rs, 0, self.offset as u16)
+	}
+
+	fn execute_on(&self, machine: &mut MipsMachine) -> BranchResult {
+		if machine.read_grf(self.rs) as i32 > 0 {
+			BranchResult::Yes(calc_branch_addr(machine.pc(), self.offset))
+		} else {
+			BranchResult::No
+		}
+	}
+}
+
+const PC_MUSK: u32 = u32::MAX >> 28 << 28;
 
 #[derive(Debug, Copy, Clone)]
 pub struct JInstr {
@@ -1340,6 +1948,7 @@ impl Display for JInstr {
 }
 
 impl Instruction for JInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::J) }
 	fn to_machine_code(&self) -> u32 {
 		gen_machine_code_j(0b000010, self.addr)
 	}
@@ -1362,6 +1971,7 @@ impl Display for JalInstr {
 }
 
 impl Instruction for JalInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Jal) }
 	fn to_machine_code(&self) -> u32 {
 		gen_machine_code_j(0b000011, self.addr)
 	}
@@ -1384,6 +1994,7 @@ impl Display for JrInstr {
 }
 
 impl Instruction for JrInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Jr) }
 	fn to_machine_code(&self) -> u32 {
 		gen_machine_code_r(0, self.rs, 0, 0, 0, 0b001000)
 	}
@@ -1405,14 +2016,16 @@ impl Display for JalrInstr {
 }
 
 impl Instruction for JalrInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Jalr) }
 	fn to_machine_code(&self) -> u32 {
 		gen_machine_code_r(0, self.rs, 0, self.rd, 0, 0b001001)
 	}
 
 	fn execute_on(&self, machine: &mut MipsMachine) -> BranchResult {
-		debug_assert_ne!(self.rs, self.rd);
+		// The target is read before the link is written, in case rs and rd are the same.
+		let target = machine.read_grf(self.rs);
 		machine.write_grf(self.rd, machine.pc() + WORD_SIZE as u32 * 2);
-		BranchResult::Yes(machine.read_grf(self.rs))
+		BranchResult::Yes(target)
 	}
 }
 
@@ -1429,6 +2042,7 @@ impl Display for MultInstr {
 }
 
 impl Instruction for MultInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Mult) }
 	fn to_machine_code(&self) -> u32 {
 		gen_machine_code_r(0, self.rs, self.rt, 0, 0, 0b011000)
 	}
@@ -1454,6 +2068,7 @@ impl Display for MultuInstr {
 }
 
 impl Instruction for MultuInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Multu) }
 	fn to_machine_code(&self) -> u32 {
 		gen_machine_code_r(0, self.rs, self.rt, 0, 0, 0b011001)
 	}
@@ -1479,6 +2094,7 @@ impl Display for DivInstr {
 }
 
 impl Instruction for DivInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Div) }
 	fn to_machine_code(&self) -> u32 {
 		gen_machine_code_r(0, self.rs, self.rt, 0, 0, 0b011010)
 	}
@@ -1510,6 +2126,7 @@ impl Display for DivuInstr {
 }
 
 impl Instruction for DivuInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Divu) }
 	fn to_machine_code(&self) -> u32 {
 		gen_machine_code_r(0, self.rs, self.rt, 0, 0, 0b011011)
 	}
@@ -1517,13 +2134,8 @@ impl Instruction for DivuInstr {
 	fn execute_on(&self, machine: &mut MipsMachine) -> BranchResult {
 		let in0 = machine.read_grf(self.rs);
 		let in1 = machine.read_grf(self.rt);
-		if in1 != 0 {
-			machine.lo = in0 / in1;
-			machine.hi = in0 % in1;
-		} else {
-			machine.lo = 0;
-			machine.hi = 0;
-		}
+		machine.lo = u32::checked_div(in0, in1).unwrap_or(0);
+		machine.hi = u32::checked_rem(in0, in1).unwrap_or(0);
 		BranchResult::None
 	}
 }
@@ -1540,6 +2152,7 @@ impl Display for MfloInstr {
 }
 
 impl Instruction for MfloInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Mflo) }
 	fn to_machine_code(&self) -> u32 {
 		gen_machine_code_r(0, 0, 0, self.rd, 0, 0b010010)
 	}
@@ -1562,6 +2175,7 @@ impl Display for MfhiInstr {
 }
 
 impl Instruction for MfhiInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Mfhi) }
 	fn to_machine_code(&self) -> u32 {
 		gen_machine_code_r(0, 0, 0, self.rd, 0, 0b010000)
 	}
@@ -1584,6 +2198,7 @@ impl Display for MtloInstr {
 }
 
 impl Instruction for MtloInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Mtlo) }
 	fn to_machine_code(&self) -> u32 {
 		gen_machine_code_r(0, self.rs, 0, 0, 0, 0b010011)
 	}
@@ -1606,6 +2221,7 @@ impl Display for MthiInstr {
 }
 
 impl Instruction for MthiInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Mthi) }
 	fn to_machine_code(&self) -> u32 {
 		gen_machine_code_r(0, self.rs, 0, 0, 0, 0b010001)
 	}
@@ -1615,3 +2231,1252 @@ impl Instruction for MthiInstr {
 		BranchResult::None
 	}
 }
+
+#[derive(Debug, Copy, Clone)]
+pub struct MovzInstr {
+	pub rs: u8,
+	pub rt: u8,
+	pub rd: u8,
+}
+
+impl Display for MovzInstr {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		write!(f, "movz ${}, ${}, ${}", self.rd, self.rs, self.rt)
+	}
+}
+
+impl Instruction for MovzInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Movz) }
+	fn to_machine_code(&self) -> u32 {
+		gen_machine_code_r(0, self.rs, self.rt, self.rd, 0, 0b001010)
+	}
+
+	fn execute_on(&self, machine: &mut MipsMachine) -> BranchResult {
+		if machine.read_grf(self.rt) == 0 {
+			machine.write_grf(self.rd, machine.read_grf(self.rs));
+		}
+		BranchResult::None
+	}
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct MovnInstr {
+	pub rs: u8,
+	pub rt: u8,
+	pub rd: u8,
+}
+
+impl Display for MovnInstr {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		write!(f, "movn ${}, ${}, ${}", self.rd, self.rs, self.rt)
+	}
+}
+
+impl Instruction for MovnInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Movn) }
+	fn to_machine_code(&self) -> u32 {
+		gen_machine_code_r(0, self.rs, self.rt, self.rd, 0, 0b001011)
+	}
+
+	fn execute_on(&self, machine: &mut MipsMachine) -> BranchResult {
+		if machine.read_grf(self.rt) != 0 {
+			machine.write_grf(self.rd, machine.read_grf(self.rs));
+		}
+		BranchResult::None
+	}
+}
+
+// MARS leaves the rt field of clz and clo zero rather than a copy of rd.
+#[derive(Debug, Copy, Clone)]
+pub struct ClzInstr {
+	pub rs: u8,
+	pub rd: u8,
+}
+
+impl Display for ClzInstr {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		write!(f, "clz ${}, ${}", self.rd, self.rs)
+	}
+}
+
+impl Instruction for ClzInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Clz) }
+	fn to_machine_code(&self) -> u32 {
+		gen_machine_code_r(0b011100, self.rs, 0, self.rd, 0, 0b100000)
+	}
+
+	fn execute_on(&self, machine: &mut MipsMachine) -> BranchResult {
+		machine.write_grf(self.rd, machine.read_grf(self.rs).leading_zeros());
+		BranchResult::None
+	}
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct CloInstr {
+	pub rs: u8,
+	pub rd: u8,
+}
+
+impl Display for CloInstr {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		write!(f, "clo ${}, ${}", self.rd, self.rs)
+	}
+}
+
+impl Instruction for CloInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Clo) }
+	fn to_machine_code(&self) -> u32 {
+		gen_machine_code_r(0b011100, self.rs, 0, self.rd, 0, 0b100001)
+	}
+
+	fn execute_on(&self, machine: &mut MipsMachine) -> BranchResult {
+		machine.write_grf(self.rd, machine.read_grf(self.rs).leading_ones());
+		BranchResult::None
+	}
+}
+
+// Like MARS, mul also leaves the full product in HI and LO.
+#[derive(Debug, Copy, Clone)]
+pub struct MulInstr {
+	pub rs: u8,
+	pub rt: u8,
+	pub rd: u8,
+}
+
+impl Display for MulInstr {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		write!(f, "mul ${}, ${}, ${}", self.rd, self.rs, self.rt)
+	}
+}
+
+impl Instruction for MulInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Mul) }
+	fn to_machine_code(&self) -> u32 {
+		gen_machine_code_r(0b011100, self.rs, self.rt, self.rd, 0, 0b000010)
+	}
+
+	// HI and LO are unpredictable after mul in MIPS32, P7 CPUs leave them unchanged.
+	fn execute_on(&self, machine: &mut MipsMachine) -> BranchResult {
+		let res = i32::wrapping_mul(machine.read_grf(self.rs) as i32, machine.read_grf(self.rt) as i32);
+		machine.write_grf(self.rd, res as u32);
+		BranchResult::None
+	}
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct MaddInstr {
+	pub rs: u8,
+	pub rt: u8,
+}
+
+impl Display for MaddInstr {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		write!(f, "madd ${}, ${}", self.rs, self.rt)
+	}
+}
+
+impl Instruction for MaddInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Madd) }
+	fn to_machine_code(&self) -> u32 {
+		gen_machine_code_r(0b011100, self.rs, self.rt, 0, 0, 0b000000)
+	}
+
+	fn execute_on(&self, machine: &mut MipsMachine) -> BranchResult {
+		let prod = i64::wrapping_mul(machine.read_grf(self.rs) as i32 as i64, machine.read_grf(self.rt) as i32 as i64);
+		machine.set_hi_lo(u64::wrapping_add(machine.hi_lo(), prod as u64));
+		BranchResult::None
+	}
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct MadduInstr {
+	pub rs: u8,
+	pub rt: u8,
+}
+
+impl Display for MadduInstr {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		write!(f, "maddu ${}, ${}", self.rs, self.rt)
+	}
+}
+
+impl Instruction for MadduInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Maddu) }
+	fn to_machine_code(&self) -> u32 {
+		gen_machine_code_r(0b011100, self.rs, self.rt, 0, 0, 0b000001)
+	}
+
+	fn execute_on(&self, machine: &mut MipsMachine) -> BranchResult {
+		let prod = u64::wrapping_mul(machine.read_grf(self.rs) as u64, machine.read_grf(self.rt) as u64);
+		machine.set_hi_lo(u64::wrapping_add(machine.hi_lo(), prod));
+		BranchResult::None
+	}
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct MsubInstr {
+	pub rs: u8,
+	pub rt: u8,
+}
+
+impl Display for MsubInstr {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		write!(f, "msub ${}, ${}", self.rs, self.rt)
+	}
+}
+
+impl Instruction for MsubInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Msub) }
+	fn to_machine_code(&self) -> u32 {
+		gen_machine_code_r(0b011100, self.rs, self.rt, 0, 0, 0b000100)
+	}
+
+	fn execute_on(&self, machine: &mut MipsMachine) -> BranchResult {
+		let prod = i64::wrapping_mul(machine.read_grf(self.rs) as i32 as i64, machine.read_grf(self.rt) as i32 as i64);
+		machine.set_hi_lo(u64::wrapping_sub(machine.hi_lo(), prod as u64));
+		BranchResult::None
+	}
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct MsubuInstr {
+	pub rs: u8,
+	pub rt: u8,
+}
+
+impl Display for MsubuInstr {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		write!(f, "msubu ${}, ${}", self.rs, self.rt)
+	}
+}
+
+impl Instruction for MsubuInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Msubu) }
+	fn to_machine_code(&self) -> u32 {
+		gen_machine_code_r(0b011100, self.rs, self.rt, 0, 0, 0b000101)
+	}
+
+	fn execute_on(&self, machine: &mut MipsMachine) -> BranchResult {
+		let prod = u64::wrapping_mul(machine.read_grf(self.rs) as u64, machine.read_grf(self.rt) as u64);
+		machine.set_hi_lo(u64::wrapping_sub(machine.hi_lo(), prod));
+		BranchResult::None
+	}
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct SyscallInstr {
+	pub code: u32,
+}
+
+impl Display for SyscallInstr {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		if self.code == 0 {
+			write!(f, "syscall")
+		} else {
+			write!(f, "syscall {}", self.code)
+		}
+	}
+}
+
+impl Instruction for SyscallInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Syscall) }
+	fn to_machine_code(&self) -> u32 {
+		debug_assert!(self.code < 1 << 20);
+		self.code << 6 | 0b001100
+	}
+
+	fn execute_on(&self, machine: &mut MipsMachine) -> BranchResult {
+		machine.handle_exception(8);
+		BranchResult::None
+	}
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct BreakInstr {
+	pub code: u32,
+}
+
+impl Display for BreakInstr {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		if self.code == 0 {
+			write!(f, "break")
+		} else {
+			write!(f, "break {}", self.code)
+		}
+	}
+}
+
+impl Instruction for BreakInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Break) }
+	fn to_machine_code(&self) -> u32 {
+		debug_assert!(self.code < 1 << 20);
+		self.code << 6 | 0b001101
+	}
+
+	fn execute_on(&self, machine: &mut MipsMachine) -> BranchResult {
+		machine.handle_exception(9);
+		BranchResult::None
+	}
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct TeqInstr {
+	pub rs: u8,
+	pub rt: u8,
+}
+
+impl Display for TeqInstr {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		write!(f, "teq ${}, ${}", self.rs, self.rt)
+	}
+}
+
+impl Instruction for TeqInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Teq) }
+	fn to_machine_code(&self) -> u32 {
+		gen_machine_code_r(0, self.rs, self.rt, 0, 0, 0b110100)
+	}
+
+	fn execute_on(&self, machine: &mut MipsMachine) -> BranchResult {
+		if machine.read_grf(self.rs) == machine.read_grf(self.rt) {
+			machine.handle_exception(13);
+		}
+		BranchResult::None
+	}
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct TneInstr {
+	pub rs: u8,
+	pub rt: u8,
+}
+
+impl Display for TneInstr {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		write!(f, "tne ${}, ${}", self.rs, self.rt)
+	}
+}
+
+impl Instruction for TneInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Tne) }
+	fn to_machine_code(&self) -> u32 {
+		gen_machine_code_r(0, self.rs, self.rt, 0, 0, 0b110110)
+	}
+
+	fn execute_on(&self, machine: &mut MipsMachine) -> BranchResult {
+		if machine.read_grf(self.rs) != machine.read_grf(self.rt) {
+			machine.handle_exception(13);
+		}
+		BranchResult::None
+	}
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct TgeInstr {
+	pub rs: u8,
+	pub rt: u8,
+}
+
+impl Display for TgeInstr {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		write!(f, "tge ${}, ${}", self.rs, self.rt)
+	}
+}
+
+impl Instruction for TgeInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Tge) }
+	fn to_machine_code(&self) -> u32 {
+		gen_machine_code_r(0, self.rs, self.rt, 0, 0, 0b110000)
+	}
+
+	fn execute_on(&self, machine: &mut MipsMachine) -> BranchResult {
+		if machine.read_grf(self.rs) as i32 >= machine.read_grf(self.rt) as i32 {
+			machine.handle_exception(13);
+		}
+		BranchResult::None
+	}
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct TgeuInstr {
+	pub rs: u8,
+	pub rt: u8,
+}
+
+impl Display for TgeuInstr {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		write!(f, "tgeu ${}, ${}", self.rs, self.rt)
+	}
+}
+
+impl Instruction for TgeuInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Tgeu) }
+	fn to_machine_code(&self) -> u32 {
+		gen_machine_code_r(0, self.rs, self.rt, 0, 0, 0b110001)
+	}
+
+	fn execute_on(&self, machine: &mut MipsMachine) -> BranchResult {
+		if machine.read_grf(self.rs) >= machine.read_grf(self.rt) {
+			machine.handle_exception(13);
+		}
+		BranchResult::None
+	}
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct TltInstr {
+	pub rs: u8,
+	pub rt: u8,
+}
+
+impl Display for TltInstr {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		write!(f, "tlt ${}, ${}", self.rs, self.rt)
+	}
+}
+
+impl Instruction for TltInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Tlt) }
+	fn to_machine_code(&self) -> u32 {
+		gen_machine_code_r(0, self.rs, self.rt, 0, 0, 0b110010)
+	}
+
+	fn execute_on(&self, machine: &mut MipsMachine) -> BranchResult {
+		if (machine.read_grf(self.rs) as i32) < machine.read_grf(self.rt) as i32 {
+			machine.handle_exception(13);
+		}
+		BranchResult::None
+	}
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct TltuInstr {
+	pub rs: u8,
+	pub rt: u8,
+}
+
+impl Display for TltuInstr {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		write!(f, "tltu ${}, ${}", self.rs, self.rt)
+	}
+}
+
+impl Instruction for TltuInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Tltu) }
+	fn to_machine_code(&self) -> u32 {
+		gen_machine_code_r(0, self.rs, self.rt, 0, 0, 0b110011)
+	}
+
+	fn execute_on(&self, machine: &mut MipsMachine) -> BranchResult {
+		if machine.read_grf(self.rs) < machine.read_grf(self.rt) {
+			machine.handle_exception(13);
+		}
+		BranchResult::None
+	}
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct TgeiInstr {
+	pub rs: u8,
+	pub imm: i16,
+}
+
+impl Display for TgeiInstr {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		write!(f, "tgei ${}, {}", self.rs, self.imm)
+	}
+}
+
+impl Instruction for TgeiInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Tgei) }
+	fn to_machine_code(&self) -> u32 {
+		gen_machine_code_i(0b000001, self.rs, 0b01000, self.imm as u16)
+	}
+
+	fn execute_on(&self, machine: &mut MipsMachine) -> BranchResult {
+		if machine.read_grf(self.rs) as i32 >= self.imm as i32 {
+			machine.handle_exception(13);
+		}
+		BranchResult::None
+	}
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct TgeiuInstr {
+	pub rs: u8,
+	pub imm: i16,
+}
+
+impl Display for TgeiuInstr {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		write!(f, "tgeiu ${}, {}", self.rs, self.imm)
+	}
+}
+
+impl Instruction for TgeiuInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Tgeiu) }
+	fn to_machine_code(&self) -> u32 {
+		gen_machine_code_i(0b000001, self.rs, 0b01001, self.imm as u16)
+	}
+
+	fn execute_on(&self, machine: &mut MipsMachine) -> BranchResult {
+		if machine.read_grf(self.rs) >= self.imm as u32 {
+			machine.handle_exception(13);
+		}
+		BranchResult::None
+	}
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct TltiInstr {
+	pub rs: u8,
+	pub imm: i16,
+}
+
+impl Display for TltiInstr {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		write!(f, "tlti ${}, {}", self.rs, self.imm)
+	}
+}
+
+impl Instruction for TltiInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Tlti) }
+	fn to_machine_code(&self) -> u32 {
+		gen_machine_code_i(0b000001, self.rs, 0b01010, self.imm as u16)
+	}
+
+	fn execute_on(&self, machine: &mut MipsMachine) -> BranchResult {
+		if (machine.read_grf(self.rs) as i32) < self.imm as i32 {
+			machine.handle_exception(13);
+		}
+		BranchResult::None
+	}
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct TltiuInstr {
+	pub rs: u8,
+	pub imm: i16,
+}
+
+impl Display for TltiuInstr {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		write!(f, "tltiu ${}, {}", self.rs, self.imm)
+	}
+}
+
+impl Instruction for TltiuInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Tltiu) }
+	fn to_machine_code(&self) -> u32 {
+		gen_machine_code_i(0b000001, self.rs, 0b01011, self.imm as u16)
+	}
+
+	fn execute_on(&self, machine: &mut MipsMachine) -> BranchResult {
+		if machine.read_grf(self.rs) < self.imm as u32 {
+			machine.handle_exception(13);
+		}
+		BranchResult::None
+	}
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct TeqiInstr {
+	pub rs: u8,
+	pub imm: i16,
+}
+
+impl Display for TeqiInstr {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		write!(f, "teqi ${}, {}", self.rs, self.imm)
+	}
+}
+
+impl Instruction for TeqiInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Teqi) }
+	fn to_machine_code(&self) -> u32 {
+		gen_machine_code_i(0b000001, self.rs, 0b01100, self.imm as u16)
+	}
+
+	fn execute_on(&self, machine: &mut MipsMachine) -> BranchResult {
+		if machine.read_grf(self.rs) == self.imm as u32 {
+			machine.handle_exception(13);
+		}
+		BranchResult::None
+	}
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct TneiInstr {
+	pub rs: u8,
+	pub imm: i16,
+}
+
+impl Display for TneiInstr {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		write!(f, "tnei ${}, {}", self.rs, self.imm)
+	}
+}
+
+impl Instruction for TneiInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Tnei) }
+	fn to_machine_code(&self) -> u32 {
+		gen_machine_code_i(0b000001, self.rs, 0b01110, self.imm as u16)
+	}
+
+	fn execute_on(&self, machine: &mut MipsMachine) -> BranchResult {
+		if machine.read_grf(self.rs) != self.imm as u32 {
+			machine.handle_exception(13);
+		}
+		BranchResult::None
+	}
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Mfc0Instr {
+	pub rt: u8,
+	pub rd: u8,
+}
+
+impl Display for Mfc0Instr {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		write!(f, "mfc0 ${}, ${}", self.rt, self.rd)
+	}
+}
+
+impl Instruction for Mfc0Instr {
+	fn instr_type(&self) -> Option<InstructionType> { None }
+	fn to_machine_code(&self) -> u32 {
+		gen_machine_code_r(0b010000, 0b00000, self.rt, self.rd, 0, 0)
+	}
+
+	fn execute_on(&self, machine: &mut MipsMachine) -> BranchResult {
+		machine.write_grf(self.rt, machine.read_cp0(self.rd));
+		BranchResult::None
+	}
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Mtc0Instr {
+	pub rt: u8,
+	pub rd: u8,
+}
+
+impl Display for Mtc0Instr {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		write!(f, "mtc0 ${}, ${}", self.rt, self.rd)
+	}
+}
+
+impl Instruction for Mtc0Instr {
+	fn instr_type(&self) -> Option<InstructionType> { None }
+	fn to_machine_code(&self) -> u32 {
+		gen_machine_code_r(0b010000, 0b00100, self.rt, self.rd, 0, 0)
+	}
+
+	fn execute_on(&self, machine: &mut MipsMachine) -> BranchResult {
+		machine.write_cp0(self.rd, machine.read_grf(self.rt));
+		BranchResult::None
+	}
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct EretInstr;
+
+impl Display for EretInstr {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		write!(f, "eret")
+	}
+}
+
+impl Instruction for EretInstr {
+	fn instr_type(&self) -> Option<InstructionType> { None }
+	fn to_machine_code(&self) -> u32 {
+		gen_machine_code_r(0b010000, 0b10000, 0, 0, 0, 0b011000)
+	}
+
+	fn execute_on(&self, machine: &mut MipsMachine) -> BranchResult {
+		machine.eret();
+		BranchResult::None
+	}
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct ReservedInstr {
+	pub code: u32,
+}
+
+impl Display for ReservedInstr {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		write!(f, ".word 0x{:08x}", self.code)
+	}
+}
+
+impl Instruction for ReservedInstr {
+	fn instr_type(&self) -> Option<InstructionType> { Some(InstructionType::Reserved) }
+	fn to_machine_code(&self) -> u32 {
+		self.code
+	}
+
+	fn execute_on(&self, machine: &mut MipsMachine) -> BranchResult {
+		machine.handle_exception(10);
+		BranchResult::None
+	}
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct ReservedInstructionError {
+	code: u32,
+}
+
+impl Display for ReservedInstructionError {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		write!(f, "reserved instruction {:08x}", self.code)
+	}
+}
+
+impl Error for ReservedInstructionError {}
+
+pub fn decode_instruction(code: u32) -> Result<Box<dyn Instruction>, ReservedInstructionError> {
+	let op = (code >> 26) as u8;
+	let rs = (code >> 21 & 0x1f) as u8;
+	let rt = (code >> 16 & 0x1f) as u8;
+	let rd = (code >> 11 & 0x1f) as u8;
+	let sa = (code >> 6 & 0x1f) as u8;
+	let func = (code & 0x3f) as u8;
+	let imm = code as u16;
+	let addr = code & 0x3ffffff;
+	let err = ReservedInstructionError { code };
+	let instr: Box<dyn Instruction> = match op {
+		0b000000 => match func {
+			0b000000 if code == 0 => Box::new(NopInstr),
+			0b000000 => Box::new(SllInstr { rt, rd, sa }),
+			0b000010 => Box::new(SrlInstr { rt, rd, sa }),
+			0b000011 => Box::new(SraInstr { rt, rd, sa }),
+			0b000100 => Box::new(SllvInstr { rs, rt, rd }),
+			0b000110 => Box::new(SrlvInstr { rs, rt, rd }),
+			0b000111 => Box::new(SravInstr { rs, rt, rd }),
+			0b001000 => Box::new(JrInstr { rs }),
+			0b001001 => Box::new(JalrInstr { rs, rd }),
+			0b001010 => Box::new(MovzInstr { rs, rt, rd }),
+			0b001011 => Box::new(MovnInstr { rs, rt, rd }),
+			0b001100 => Box::new(SyscallInstr { code: code >> 6 & 0xfffff }),
+			0b001101 => Box::new(BreakInstr { code: code >> 6 & 0xfffff }),
+			0b010000 => Box::new(MfhiInstr { rd }),
+			0b010001 => Box::new(MthiInstr { rs }),
+			0b010010 => Box::new(MfloInstr { rd }),
+			0b010011 => Box::new(MtloInstr { rs }),
+			0b011000 => Box::new(MultInstr { rs, rt }),
+			0b011001 => Box::new(MultuInstr { rs, rt }),
+			0b011010 => Box::new(DivInstr { rs, rt }),
+			0b011011 => Box::new(DivuInstr { rs, rt }),
+			0b100000 => Box::new(AddInstr { rs, rt, rd }),
+			0b100001 => Box::new(AdduInstr { rs, rt, rd }),
+			0b100010 => Box::new(SubInstr { rs, rt, rd }),
+			0b100011 => Box::new(SubuInstr { rs, rt, rd }),
+			0b100100 => Box::new(AndInstr { rs, rt, rd }),
+			0b100101 => Box::new(OrInstr { rs, rt, rd }),
+			0b100110 => Box::new(XorInstr { rs, rt, rd }),
+			0b100111 => Box::new(NorInstr { rs, rt, rd }),
+			0b101010 => Box::new(SltInstr { rs, rt, rd }),
+			0b101011 => Box::new(SltuInstr { rs, rt, rd }),
+			0b110000 => Box::new(TgeInstr { rs, rt }),
+			0b110001 => Box::new(TgeuInstr { rs, rt }),
+			0b110010 => Box::new(TltInstr { rs, rt }),
+			0b110011 => Box::new(TltuInstr { rs, rt }),
+			0b110100 => Box::new(TeqInstr { rs, rt }),
+			0b110110 => Box::new(TneInstr { rs, rt }),
+			_ => return Err(err),
+		},
+		0b000001 => match rt {
+			0b00000 => Box::new(BltzInstr { rs, offset: imm as i16 }),
+			0b00001 => Box::new(BgezInstr { rs, offset: imm as i16 }),
+			0b01000 => Box::new(TgeiInstr { rs, imm: imm as i16 }),
+			0b01001 => Box::new(TgeiuInstr { rs, imm: imm as i16 }),
+			0b01010 => Box::new(TltiInstr { rs, imm: imm as i16 }),
+			0b01011 => Box::new(TltiuInstr { rs, imm: imm as i16 }),
+			0b01100 => Box::new(TeqiInstr { rs, imm: imm as i16 }),
+			0b01110 => Box::new(TneiInstr { rs, imm: imm as i16 }),
+			0b10000 => Box::new(BltzalInstr { rs, offset: imm as i16 }),
+			0b10001 => Box::new(BgezalInstr { rs, offset: imm as i16 }),
+			_ => return Err(err),
+		},
+		0b011100 => match func {
+			0b000000 => Box::new(MaddInstr { rs, rt }),
+			0b000001 => Box::new(MadduInstr { rs, rt }),
+			0b000010 => Box::new(MulInstr { rs, rt, rd }),
+			0b000100 => Box::new(MsubInstr { rs, rt }),
+			0b000101 => Box::new(MsubuInstr { rs, rt }),
+			0b100000 => Box::new(ClzInstr { rs, rd }),
+			0b100001 => Box::new(CloInstr { rs, rd }),
+			_ => return Err(err),
+		},
+		0b010000 => match rs {
+			0b00000 => Box::new(Mfc0Instr { rt, rd }),
+			0b00100 => Box::new(Mtc0Instr { rt, rd }),
+			0b10000 if func == 0b011000 => Box::new(EretInstr),
+			_ => return Err(err),
+		},
+		0b000010 => Box::new(JInstr { addr }),
+		0b000011 => Box::new(JalInstr { addr }),
+		0b000100 => Box::new(BeqInstr { rs, rt, offset: imm as i16 }),
+		0b000101 => Box::new(BneInstr { rs, rt, offset: imm as i16 }),
+		0b000110 => Box::new(BlezInstr { rs, offset: imm as i16 }),
+		0b000111 => Box::new(BgtzInstr { rs, offset: imm as i16 }),
+		0b010100 => Box::new(BeqlInstr { rs, rt, offset: imm as i16 }),
+		0b010101 => Box::new(BnelInstr { rs, rt, offset: imm as i16 }),
+		0b001000 => Box::new(AddiInstr { rs, rt, imm: imm as i16 }),
+		0b001001 => Box::new(AddiuInstr { rs, rt, imm: imm as i16 }),
+		0b001010 => Box::new(SltiInstr { rs, rt, imm: imm as i16 }),
+		0b001011 => Box::new(SltiuInstr { rs, rt, imm: imm as i16 }),
+		0b001100 => Box::new(AndiInstr { rs, rt, imm }),
+		0b001101 => Box::new(OriInstr { rs, rt, imm }),
+		0b001110 => Box::new(XoriInstr { rs, rt, imm }),
+		0b001111 => Box::new(LuiInstr { rt, imm }),
+		0b100000 => Box::new(LbInstr { base: rs, rt, offset: imm as i16 }),
+		0b100001 => Box::new(LhInstr { base: rs, rt, offset: imm as i16 }),
+		0b100010 => Box::new(LwlInstr { base: rs, rt, offset: imm as i16 }),
+		0b100011 => Box::new(LwInstr { base: rs, rt, offset: imm as i16 }),
+		0b100100 => Box::new(LbuInstr { base: rs, rt, offset: imm as i16 }),
+		0b100101 => Box::new(LhuInstr { base: rs, rt, offset: imm as i16 }),
+		0b100110 => Box::new(LwrInstr { base: rs, rt, offset: imm as i16 }),
+		0b101000 => Box::new(SbInstr { base: rs, rt, offset: imm as i16 }),
+		0b101001 => Box::new(ShInstr { base: rs, rt, offset: imm as i16 }),
+		0b101010 => Box::new(SwlInstr { base: rs, rt, offset: imm as i16 }),
+		0b101011 => Box::new(SwInstr { base: rs, rt, offset: imm as i16 }),
+		0b101110 => Box::new(SwrInstr { base: rs, rt, offset: imm as i16 }),
+		0b110000 => Box::new(LlInstr { base: rs, rt, offset: imm as i16 }),
+		0b111000 => Box::new(ScInstr { base: rs, rt, offset: imm as i16 }),
+		_ => return Err(err),
+	};
+	// Fields an instruction doesn't use must be zero, otherwise the encoding is reserved.
+	if instr.to_machine_code() == code {
+		Ok(instr)
+	} else {
+		Err(err)
+	}
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+	use super::*;
+	use strum::AsStaticRef;
+
+	// Assembles `src` into a machine with exception handling, using its .ktext section as the handler or
+	// code_handler.asm without one.
+	pub fn load_program(src: &str, delayed_branching: bool) -> MipsMachine {
+		let program = crate::asm::assemble(src).unwrap();
+		let handler = if program.ktext.instrs.is_empty() {
+			crate::asm::assemble(include_str!("code_handler.asm")).unwrap().ktext.machine_code()
+		} else {
+			program.ktext.machine_code()
+		};
+		let mut machine = MipsMachine::new(delayed_branching, true, 1024, &handler);
+		for code in program.text.machine_code() {
+			machine.push_text(code);
+		}
+		machine
+	}
+
+	pub fn run_program(src: &str, delayed_branching: bool) -> MipsMachine {
+		let mut machine = load_program(src, delayed_branching);
+		assert!(machine.run(1 << 16));
+		machine
+	}
+
+	fn all_encodable_instructions() -> Vec<Box<dyn Instruction>> {
+		let mut instrs: Vec<Box<dyn Instruction>> = vec![Box::new(NopInstr), Box::new(EretInstr)];
+		for &code in &[0u32, 1, 0xfffff] {
+			instrs.push(Box::new(SyscallInstr { code }));
+			instrs.push(Box::new(BreakInstr { code }));
+		}
+		let regs = [(0u8, 1u8, 2u8), (31, 17, 5), (8, 8, 30)];
+		for &(rs, rt, rd) in &regs {
+			instrs.push(Box::new(AddInstr { rs, rt, rd }));
+			instrs.push(Box::new(AdduInstr { rs, rt, rd }));
+			instrs.push(Box::new(SubInstr { rs, rt, rd }));
+			instrs.push(Box::new(SubuInstr { rs, rt, rd }));
+			instrs.push(Box::new(SllvInstr { rs, rt, rd }));
+			instrs.push(Box::new(SrlvInstr { rs, rt, rd }));
+			instrs.push(Box::new(SravInstr { rs, rt, rd }));
+			instrs.push(Box::new(AndInstr { rs, rt, rd }));
+			instrs.push(Box::new(OrInstr { rs, rt, rd }));
+			instrs.push(Box::new(XorInstr { rs, rt, rd }));
+			instrs.push(Box::new(NorInstr { rs, rt, rd }));
+			instrs.push(Box::new(SltInstr { rs, rt, rd }));
+			instrs.push(Box::new(SltuInstr { rs, rt, rd }));
+			instrs.push(Box::new(JrInstr { rs }));
+			instrs.push(Box::new(JalrInstr { rs, rd }));
+			instrs.push(Box::new(MultInstr { rs, rt }));
+			instrs.push(Box::new(MultuInstr { rs, rt }));
+			instrs.push(Box::new(DivInstr { rs, rt }));
+			instrs.push(Box::new(DivuInstr { rs, rt }));
+			instrs.push(Box::new(MfloInstr { rd }));
+			instrs.push(Box::new(MfhiInstr { rd }));
+			instrs.push(Box::new(MtloInstr { rs }));
+			instrs.push(Box::new(MthiInstr { rs }));
+			instrs.push(Box::new(MovzInstr { rs, rt, rd }));
+			instrs.push(Box::new(MovnInstr { rs, rt, rd }));
+			instrs.push(Box::new(ClzInstr { rs, rd }));
+			instrs.push(Box::new(CloInstr { rs, rd }));
+			instrs.push(Box::new(MulInstr { rs, rt, rd }));
+			instrs.push(Box::new(MaddInstr { rs, rt }));
+			instrs.push(Box::new(MadduInstr { rs, rt }));
+			instrs.push(Box::new(MsubInstr { rs, rt }));
+			instrs.push(Box::new(MsubuInstr { rs, rt }));
+			instrs.push(Box::new(TeqInstr { rs, rt }));
+			instrs.push(Box::new(TneInstr { rs, rt }));
+			instrs.push(Box::new(TgeInstr { rs, rt }));
+			instrs.push(Box::new(TgeuInstr { rs, rt }));
+			instrs.push(Box::new(TltInstr { rs, rt }));
+			instrs.push(Box::new(TltuInstr { rs, rt }));
+			instrs.push(Box::new(Mfc0Instr { rt, rd }));
+			instrs.push(Box::new(Mtc0Instr { rt, rd }));
+			for &sa in &[1u8, 16, 31] {
+				instrs.push(Box::new(SllInstr { rt, rd, sa }));
+				instrs.push(Box::new(SrlInstr { rt, rd, sa }));
+				instrs.push(Box::new(SraInstr { rt, rd, sa }));
+			}
+			for &imm in &[0u16, 1, 0x7fff, 0x8000, 0xffff] {
+				let offset = imm as i16;
+				instrs.push(Box::new(AddiInstr { rs, rt, imm: offset }));
+				instrs.push(Box::new(AddiuInstr { rs, rt, imm: offset }));
+				instrs.push(Box::new(SltiInstr { rs, rt, imm: offset }));
+				instrs.push(Box::new(SltiuInstr { rs, rt, imm: offset }));
+				instrs.push(Box::new(AndiInstr { rs, rt, imm }));
+				instrs.push(Box::new(OriInstr { rs, rt, imm }));
+				instrs.push(Box::new(XoriInstr { rs, rt, imm }));
+				instrs.push(Box::new(LuiInstr { rt, imm }));
+				instrs.push(Box::new(LbInstr { base: rs, rt, offset }));
+				instrs.push(Box::new(LbuInstr { base: rs, rt, offset }));
+				instrs.push(Box::new(LhInstr { base: rs, rt, offset }));
+				instrs.push(Box::new(LhuInstr { base: rs, rt, offset }));
+				instrs.push(Box::new(LwInstr { base: rs, rt, offset }));
+				instrs.push(Box::new(SbInstr { base: rs, rt, offset }));
+				instrs.push(Box::new(ShInstr { base: rs, rt, offset }));
+				instrs.push(Box::new(SwInstr { base: rs, rt, offset }));
+				instrs.push(Box::new(LwlInstr { base: rs, rt, offset }));
+				instrs.push(Box::new(LwrInstr { base: rs, rt, offset }));
+				instrs.push(Box::new(SwlInstr { base: rs, rt, offset }));
+				instrs.push(Box::new(SwrInstr { base: rs, rt, offset }));
+				instrs.push(Box::new(LlInstr { base: rs, rt, offset }));
+				instrs.push(Box::new(ScInstr { base: rs, rt, offset }));
+				instrs.push(Box::new(BeqInstr { rs, rt, offset }));
+				instrs.push(Box::new(BneInstr { rs, rt, offset }));
+				instrs.push(Box::new(BlezInstr { rs, offset }));
+				instrs.push(Box::new(BltzInstr { rs, offset }));
+				instrs.push(Box::new(BgezInstr { rs, offset }));
+				instrs.push(Box::new(BgtzInstr { rs, offset }));
+				instrs.push(Box::new(BltzalInstr { rs, offset }));
+				instrs.push(Box::new(BgezalInstr { rs, offset }));
+				instrs.push(Box::new(BeqlInstr { rs, rt, offset }));
+				instrs.push(Box::new(BnelInstr { rs, rt, offset }));
+				instrs.push(Box::new(TgeiInstr { rs, imm: offset }));
+				instrs.push(Box::new(TgeiuInstr { rs, imm: offset }));
+				instrs.push(Box::new(TltiInstr { rs, imm: offset }));
+				instrs.push(Box::new(TltiuInstr { rs, imm: offset }));
+				instrs.push(Box::new(TeqiInstr { rs, imm: offset }));
+				instrs.push(Box::new(TneiInstr { rs, imm: offset }));
+			}
+		}
+		for &addr in &[0u32, 0xc00, 0x3ffffff] {
+			instrs.push(Box::new(JInstr { addr }));
+			instrs.push(Box::new(JalInstr { addr }));
+		}
+		instrs
+	}
+
+	#[test]
+	fn decode_round_trip() {
+		for instr in all_encodable_instructions() {
+			let code = instr.to_machine_code();
+			let decoded = decode_instruction(code)
+				.unwrap_or_else(|e| panic!("failed to decode \"{}\": {}", instr, e));
+			assert_eq!(decoded.to_machine_code(), code);
+			assert_eq!(decoded.to_string(), instr.to_string());
+			assert_eq!(decoded.instr_type(), instr.instr_type());
+			if let Some(instr_type) = decoded.instr_type() {
+				assert_eq!(Some(instr_type.as_static()), instr.to_string().split_whitespace().next());
+			}
+		}
+	}
+
+	#[test]
+	fn decode_reserved() {
+		let codes = [
+			0xfc000000, // unused opcode
+			0x0000003f, // unused funct
+			0x04020000, // unused regimm rt
+			0x00221861, // addu with non-zero shamt
+			0x00200008 | 1 << 11, // jr with non-zero rd
+			0x3c210000, // lui with non-zero rs
+			0x00430018 | 1 << 11, // mult with non-zero rd
+			0x42000019, // eret with wrong funct
+			0x40016801, // mfc0 with non-zero sel
+			0x7000003f, // unused special2 funct
+			0x70221820, // clz with non-zero rt
+		];
+		for &code in &codes {
+			assert_eq!(decode_instruction(code).err(), Some(ReservedInstructionError { code }));
+		}
+	}
+
+	#[test]
+	fn exception_runs_handler() {
+		let machine = run_program("
+			lui $2, 0x7fff
+			ori $2, $2, 0xffff
+			beq $0, $0, skip
+			add $3, $2, $2
+			ori $4, $0, 1
+		skip:
+			ori $5, $0, 2
+		", true);
+		// The handler returns to EPC + 4, which re-executes the faulting delay slot outside the branch.
+		assert_eq!(machine.read_cp0(CP0_EPC), 0x3010);
+		assert_eq!(machine.read_cp0(CP0_CAUSE), 12 << CAUSE_EXC_CODE_SHIFT);
+		assert_eq!(machine.read_cp0(CP0_STATUS), STATUS_RESET);
+		assert_eq!(machine.read_cp0(CP0_PRID), PRID);
+		assert_eq!(&machine.grf()[3..6], &[0, 1, 2]);
+	}
+
+	#[test]
+	fn timer_interrupt() {
+		let machine = run_program("
+			ori $1, $0, 3
+			sw $1, 0x7f04($0)
+			ori $1, $0, 9
+			sw $1, 0x7f00($0)
+			ori $2, $0, 0x401
+			mtc0 $2, $12
+			nop
+			nop
+			nop
+			ori $3, $0, 1
+		.ktext
+			mfc0 $26, $13
+			mfc0 $27, $14
+			sw $0, 0x7f00($0)
+			eret
+		", true);
+		assert_eq!(machine.grf()[26], 1 << CAUSE_IP_SHIFT);
+		assert_eq!(machine.grf()[27], 0x3024);
+		assert_eq!(machine.grf()[3], 1);
+		assert_eq!(machine.load(TIMER_ADDRS[0] + 8, AccessSize::Word), Some(0));
+	}
+
+	#[test]
+	fn external_interrupt_held_after_eret() {
+		let mut machine = load_program("
+			ori $3, $0, 1
+		.ktext
+			addiu $26, $26, 1
+			eret
+		", true);
+		machine.interrupt();
+		assert!(machine.run(1 << 16));
+		// Each pass through the handler takes two of the cycles the line is held for.
+		assert_eq!(machine.grf()[26], EXTERNAL_IRQ_CYCLES / 2);
+		assert_eq!(machine.grf()[3], 1);
+		assert_eq!(machine.read_cp0(CP0_CAUSE) >> CAUSE_IP_SHIFT & 0b111111, 0);
+	}
+
+	#[test]
+	fn device_access() {
+		let mut machine = MipsMachine::new(true, true, 1024, &[]);
+		let preset = TIMER_ADDRS[1] + 4;
+		assert!(machine.store(preset, AccessSize::Word, 5));
+		assert_eq!(machine.load(preset, AccessSize::Word), Some(5));
+		assert_eq!(machine.load(preset, AccessSize::Byte), None);
+		assert_eq!(machine.load(preset + 2, AccessSize::Half), None);
+		assert!(!machine.store(preset, AccessSize::Half, 5));
+		assert!(!machine.store(preset + 4, AccessSize::Word, 5));
+		assert_eq!(machine.load(preset + 4, AccessSize::Word), Some(0));
+		assert_eq!(machine.load(preset + 8, AccessSize::Word), None);
+	}
+
+	#[test]
+	#[should_panic]
+	fn memory_overlapping_devices() {
+		MipsMachine::new(true, true, MAX_MEM_SIZE + 1, &[]);
+	}
+
+	#[test]
+	#[should_panic]
+	fn overlapping_devices() {
+		let mut bus = Bus::default();
+		bus.attach(TIMER_ADDRS[0], None, Box::<Timer>::default());
+		bus.attach(TIMER_ADDRS[0] + 4, None, Box::<Timer>::default());
+	}
+
+	#[test]
+	fn reserved_instruction_in_delay_slot() {
+		let mut machine = load_program("
+			beq $0, $0, 2
+			.word 0xfc000000
+		", true);
+		machine.step();
+		machine.step();
+		assert_eq!(machine.pc(), HANDLER_ADDR);
+		assert_eq!(machine.read_cp0(CP0_EPC), TEXT_START_ADDR);
+		assert_eq!(machine.read_cp0(CP0_CAUSE), CAUSE_BD | 10 << CAUSE_EXC_CODE_SHIFT);
+	}
+
+	#[test]
+	fn backward_branch_loop() {
+		let src = "
+			addiu $1, $0, 3
+		loop:
+			addiu $2, $2, 1
+			addiu $1, $1, -1
+			bne $1, $0, loop
+			addiu $3, $3, 1
+		";
+		for &(delayed_branching, slot_count) in &[(true, 3), (false, 1)] {
+			let machine = run_program(src, delayed_branching);
+			assert_eq!(&machine.grf()[1..4], &[0, 3, slot_count]);
+		}
+	}
+
+	#[test]
+	fn subroutine_call_returns() {
+		let machine = run_program("
+			ori $29, $0, 1024
+			jal outer
+			nop
+			addiu $3, $3, 1
+			j end
+			nop
+		inner:
+			addiu $1, $1, 1
+			jr $31
+			nop
+		outer:
+			addiu $29, $29, -8
+			sw $31, 4($29)
+			jal inner
+			nop
+			addiu $2, $2, 1
+			lw $31, 4($29)
+			jr $31
+			addiu $29, $29, 8
+		end:
+		", true);
+		assert_eq!(&machine.grf()[1..4], &[1, 1, 1]);
+		assert_eq!(machine.grf()[29], 1024);
+		assert_eq!(machine.grf()[31], 0x300c);
+	}
+
+	#[test]
+	fn accumulate_and_conditional_move() {
+		let machine = run_program("
+			addiu $1, $0, -3
+			addiu $2, $0, 5
+			mult $1, $2
+			madd $2, $2
+			maddu $1, $2
+			msub $1, $1
+			mflo $3
+			mfhi $4
+			movz $5, $1, $0
+			movn $6, $1, $0
+			clz $7, $2
+			clo $8, $1
+			mul $9, $1, $1
+			mfhi $10
+		", true);
+		// -15 + 25 + 0xfffffffd * 5 - 9 as a 64-bit sum.
+		let expected = (-15i64 + 25 - 9) as u64 + 0xfffffffd * 5;
+		// mul leaves HI as msub wrote it.
+		let hi = (expected >> 32) as u32;
+		assert_eq!(&machine.grf()[3..11], &[expected as u32, hi, (-3i32) as u32, 0, 29, 30, 9, hi]);
+		assert!(!machine.grf_log().iter().any(|x| x.addr() == 6));
+	}
+
+	#[test]
+	fn link_and_likely_branches() {
+		let src = "
+			addiu $1, $0, 1
+			bltzal $1, skip
+			addiu $2, $2, 1
+			beql $1, $0, skip
+			addiu $3, $3, 1
+			bnel $1, $0, skip
+			addiu $4, $4, 1
+			addiu $5, $5, 1
+		skip:
+		";
+		for &(delayed_branching, slots) in &[(true, [1, 0, 1, 0]), (false, [1, 1, 0, 0])] {
+			let machine = run_program(src, delayed_branching);
+			assert_eq!(&machine.grf()[2..6], &slots);
+			assert_eq!(machine.grf()[31], 0x300c);
+		}
+	}
+
+	#[test]
+	fn trap_raises_exception() {
+		let mut machine = load_program("
+			addiu $1, $0, -1
+			tltu $1, $0
+			tgei $1, 0
+			tne $1, $1
+			tlti $1, 0
+		", true);
+		for _ in 0..5 {
+			machine.step();
+		}
+		assert_eq!(machine.pc(), HANDLER_ADDR);
+		assert_eq!(machine.read_cp0(CP0_EPC), TEXT_START_ADDR + 16);
+		assert_eq!(machine.read_cp0(CP0_CAUSE), 13 << CAUSE_EXC_CODE_SHIFT);
+	}
+
+	#[test]
+	fn syscall_and_break() {
+		let cases = [
+			("j 0xc02\nsyscall", TEXT_START_ADDR, CAUSE_BD | 8 << CAUSE_EXC_CODE_SHIFT),
+			("nop\nbreak 3", TEXT_START_ADDR + 4, 9 << CAUSE_EXC_CODE_SHIFT),
+		];
+		for &(src, epc, cause) in &cases {
+			let mut machine = load_program(src, true);
+			machine.step();
+			machine.step();
+			assert_eq!(machine.pc(), HANDLER_ADDR);
+			assert_eq!(machine.read_cp0(CP0_EPC), epc);
+			assert_eq!(machine.read_cp0(CP0_CAUSE), cause);
+		}
+	}
+
+	#[test]
+	fn partial_word_access() {
+		let machine = run_program("
+			lui $1, 0x1122
+			ori $1, $1, 0x3344
+			sw $1, 0($0)
+			addiu $2, $0, -1
+			addiu $3, $0, -1
+			lwl $2, 1($0)
+			lwr $3, 1($0)
+			swl $1, 5($0)
+			swr $1, 10($0)
+		", true);
+		assert_eq!(&machine.grf()[2..4], &[0x3344ffff, 0xff112233]);
+		assert_eq!(&machine.mem()[1..3], &[0x00001122, 0x33440000]);
+		let mem_log = machine.mem_log().iter().map(|x| x.to_string()).collect::<Vec<_>>();
+		assert_eq!(&mem_log[1..], &["@0000301c: *00000004 <= 00001122", "@00003020: *00000008 <= 33440000"]);
+	}
+
+	#[test]
+	fn load_linked_store_conditional() {
+		let machine = run_program("
+			ori $1, $0, 5
+			ll $2, 0x100($0)
+			sc $1, 0x100($0)
+			sc $1, 0x104($0)
+			ll $3, 0x100($0)
+			teq $0, $0
+			sc $3, 0x104($0)
+		", true);
+		assert_eq!(&machine.grf()[1..4], &[0, 0, 0]);
+		assert_eq!(&machine.mem()[0x40..0x42], &[5, 0]);
+		assert_eq!(machine.mem_log().iter().filter(|x| x.addr() >= 0x100).count(), 1);
+	}
+}
@@ -11,42 +11,73 @@ extern crate tokio;
 extern crate rand;
 extern crate rand_distr;
 
+mod asm;
+mod coverage;
 mod gen;
 mod log;
 mod machine;
+mod profile;
+mod shrink;
+mod timer;
 
 use std::cell::RefCell;
 use std::collections::HashSet;
 use std::error::Error;
 use std::fmt::{self, Display, Formatter};
-use std::process::Stdio;
+use std::io;
+use std::num::ParseIntError;
+use std::path::{Path, PathBuf};
+use std::process::{Output, Stdio};
 use std::str::FromStr;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicU32, Ordering};
 
 use futures::prelude::*;
 use futures::channel::oneshot;
+use rand::prelude::*;
+use rand::rngs::StdRng;
 use strum::{AsStaticRef, IntoEnumIterator, VariantNames};
-use tokio::fs::File;
-use tokio::io::AsyncWriteExt;
 use tokio::process::Command;
 #[cfg(unix)]
 use tokio::signal::unix::{SignalKind, signal};
 
 use gen::{InstructionType, InstructionGenerator};
-use log::LogEntry;
-use machine::{MipsMachine, Instruction, JInstr};
+use log::{GrfLogEntry, LogEntry, MemLogEntry, ParseLogError};
+use machine::{MipsMachine, Instruction, JInstr, NopInstr, ReservedInstr, decode_instruction};
+use coverage::{Coverage, HazardCounts};
+use profile::Profile;
+use shrink::Program;
 
-const HANDLER_CODE: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/code_handler.txt"));
+const HANDLER_SRC: &str = include_str!("code_handler.asm");
+
+lazy_static! {
+	static ref HANDLER: Vec<u32> = asm::assemble(HANDLER_SRC).unwrap().ktext.machine_code();
+}
+
+// How a subject's output went wrong, leaving out the addresses that move while a test is shrunk.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum FailureKind {
+	SubjectError,
+	WrongPc,
+	WrongGrfWrite(u8),
+	WrongMemWrite,
+	TooManyWrites,
+	TooFewWrites,
+}
 
 #[derive(Debug)]
 struct TestFailureError {
 	reason: String,
+	seed: Option<u64>,
+	kind: Option<FailureKind>,
 }
 
 impl Display for TestFailureError {
 	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-		write!(f, "Test failed: {}", self.reason)
+		match self.seed {
+			Some(seed) => write!(f, "Test failed (seed {}): {}", seed, self.reason),
+			None => write!(f, "Test failed: {}", self.reason),
+		}
 	}
 }
 
@@ -54,8 +85,311 @@ impl Error for TestFailureError {}
 
 impl TestFailureError {
 	fn new(reason: String) -> Self {
-		Self { reason }
+		Self { reason, seed: None, kind: None }
+	}
+
+	fn with_seed(mut self, seed: u64) -> Self {
+		self.seed = Some(seed);
+		self
+	}
+
+	fn with_kind(mut self, kind: FailureKind) -> Self {
+		self.kind = Some(kind);
+		self
+	}
+}
+
+fn check_machine_code(code: &[u32]) -> Result<(), TestFailureError> {
+	for (i, code) in code.iter().enumerate() {
+		decode_instruction(*code).map_err(|e| TestFailureError::new(format!("line {}: {}", i + 1, e)))?;
+	}
+	Ok(())
+}
+
+fn parse_machine_code(data: &str) -> Result<Vec<u32>, ParseIntError> {
+	data.lines()
+		.map(str::trim)
+		.filter(|line| !line.is_empty())
+		.map(|line| u32::from_str_radix(line, 16))
+		.collect()
+}
+
+#[derive(Clone)]
+struct GenOptions {
+	no_db: bool,
+	no_exc: bool,
+	mem_size: usize,
+	instr_count: u32,
+	timer: bool,
+	loop_bound: u16,
+	subroutines: bool,
+	coverage: bool,
+	instr_set: Arc<Vec<InstructionType>>,
+	handler: Arc<Vec<u32>>,
+	profile: Arc<Profile>,
+}
+
+struct TestData {
+	code: Vec<u32>,
+	asm_data: Vec<u8>,
+	code_data: Vec<u8>,
+	grf_log_data: Vec<u8>,
+	mem_log_data: Vec<u8>,
+	irq_log_data: Vec<u8>,
+	machine: MipsMachine,
+}
+
+fn log_data(machine: &MipsMachine) -> (Vec<u8>, Vec<u8>) {
+	let mut grf_log_data = Vec::new();
+	for log in machine.grf_log() {
+		grf_log_data.extend(format!("{}\n", log).as_bytes());
+	}
+	let mut mem_log_data = Vec::new();
+	for log in machine.mem_log() {
+		mem_log_data.extend(format!("{}\n", log).as_bytes());
+	}
+	(grf_log_data, mem_log_data)
+}
+
+fn irq_log_data(irqs: &HashSet<u32>, instr_count: usize) -> Vec<u8> {
+	let mut irq_log_data = Vec::new();
+	for i in 0..instr_count {
+		let addr = (i * machine::WORD_SIZE) as u32 + machine::TEXT_START_ADDR;
+		let flag = if irqs.contains(&addr) { 1 } else { 0 };
+		irq_log_data.extend(format!("{}\n", flag).as_bytes());
+	}
+	irq_log_data
+}
+
+fn gen_test(opts: &GenOptions, seed: u64, seen_hazards: Option<HazardCounts>) -> TestData {
+	let mut code = Vec::new();
+	let mut asm_data = Vec::new();
+	let mut code_data = Vec::new();
+	let mut machine = MipsMachine::new(!opts.no_db, !opts.no_exc, opts.mem_size, &opts.handler);
+	if opts.coverage {
+		machine.enable_coverage();
+	}
+	let generator = InstructionGenerator::new(
+		&mut machine, &opts.instr_set, opts.instr_count, opts.timer, opts.loop_bound, opts.subroutines, seed,
+	).with_profile(&opts.profile);
+	let generator = match seen_hazards {
+		Some(seen_hazards) => generator.with_feedback(seen_hazards),
+		None => generator,
+	};
+	for instr in generator {
+		code.push(instr.to_machine_code());
+		asm_data.extend(format!("{}\n", instr).as_bytes());
+		code_data.extend(format!("{:08x}\n", instr.to_machine_code()).as_bytes());
+	}
+	if !opts.no_exc {
+		let instr = JInstr { addr: 16384 };
+		machine.push_text(instr.to_machine_code());
+		machine.push_text(NopInstr.to_machine_code());
+		machine.run(machine::HANDLER_MAX_STEPS);
+		machine.force_exception(machine.pc(), 4);
+		code.extend(&[instr.to_machine_code(), NopInstr.to_machine_code()]);
+		asm_data.extend(format!("{}\nnop\n", instr).as_bytes());
+		code_data.extend(format!("{:08x}\n00000000\n", instr.to_machine_code()).as_bytes());
+	}
+	let (grf_log_data, mem_log_data) = log_data(&machine);
+	let irq_log_data = irq_log_data(machine.irq_log(), opts.instr_count as usize);
+	TestData { code, asm_data, code_data, grf_log_data, mem_log_data, irq_log_data, machine }
+}
+
+async fn write_test(dir: &Path, test: &TestData, handler_code: &[u8]) -> io::Result<()> {
+	tokio::fs::write(dir.join("test.asm"), &test.asm_data).await?;
+	tokio::fs::write(dir.join("code.txt"), &test.code_data).await?;
+	tokio::fs::write(dir.join("code_handler.txt"), handler_code).await?;
+	tokio::fs::write(dir.join("irqs.txt"), &test.irq_log_data).await?;
+	tokio::fs::write(dir.join("std-grf.log"), &test.grf_log_data).await?;
+	tokio::fs::write(dir.join("std-mem.log"), &test.mem_log_data).await?;
+	Ok(())
+}
+
+fn parse_log(data: &str) -> Result<(Vec<GrfLogEntry>, Vec<MemLogEntry>), ParseLogError> {
+	let mut grf_log = Vec::new();
+	let mut mem_log = Vec::new();
+	for line in data.lines().filter(|line| !line.trim().is_empty()) {
+		match line.parse::<LogEntry>()? {
+			LogEntry::Grf(entry) => grf_log.push(entry),
+			LogEntry::Mem(entry) => mem_log.push(entry),
+		}
+	}
+	Ok((grf_log, mem_log))
+}
+
+fn subject_command(subject_path: &Path, dir: &Path) -> io::Result<std::process::Command> {
+	let mut command = std::process::Command::new(std::fs::canonicalize(subject_path)?);
+	command.current_dir(dir).stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::piped());
+	Ok(command)
+}
+
+fn check_subject_output(grf_log: &[GrfLogEntry], mem_log: &[MemLogEntry], subject_res: &Output) -> Result<(), TestFailureError> {
+	let subject_log = String::from_utf8_lossy(&subject_res.stdout);
+	if !subject_res.status.success() {
+		return Err(TestFailureError::new(format!(
+			"failed to run the test subject.\nstdout:\n{}\nstderr:\n{}",
+			subject_log, String::from_utf8_lossy(&subject_res.stderr),
+		)).with_kind(FailureKind::SubjectError));
+	}
+	let mut grf_id = 0;
+	let mut mem_id = 0;
+	for (i, subject_line) in subject_log.lines().enumerate() {
+		match subject_line.parse::<LogEntry>() {
+			Ok(LogEntry::Grf(grf_entry)) => {
+				if grf_entry.addr() == 0 { continue; }
+				if let Some(std_entry) = grf_log.get(grf_id) {
+					if grf_entry != *std_entry {
+						let kind = if grf_entry.pc() != std_entry.pc() {
+							FailureKind::WrongPc
+						} else {
+							FailureKind::WrongGrfWrite(std_entry.addr())
+						};
+						return Err(TestFailureError::new(format!(
+							"got \"{}\" at line {}, but expected \"{}\"",
+							grf_entry, i + 1, std_entry,
+						)).with_kind(kind));
+					}
+					grf_id += 1;
+				} else {
+					return Err(TestFailureError::new(format!(
+						"got \"{}\" at line {}, but standard output has ended.",
+						grf_entry, i + 1,
+					)).with_kind(FailureKind::TooManyWrites));
+				}
+			}
+			Ok(LogEntry::Mem(mem_entry)) => {
+				if let Some(std_entry) = mem_log.get(mem_id) {
+					if mem_entry != *std_entry {
+						let kind = if mem_entry.pc() != std_entry.pc() {
+							FailureKind::WrongPc
+						} else {
+							FailureKind::WrongMemWrite
+						};
+						return Err(TestFailureError::new(format!(
+							"got \"{}\" at line {}, but expected \"{}\"",
+							mem_entry, i + 1, std_entry,
+						)).with_kind(kind));
+					}
+					mem_id += 1;
+				} else {
+					return Err(TestFailureError::new(format!(
+						"got \"{}\" at line {}, but standard output has ended.",
+						mem_entry, i + 1,
+					)).with_kind(FailureKind::TooManyWrites));
+				}
+			}
+			Err(_) => (),
+		}
+	}
+	if let Some(entry) = grf_log.get(grf_id) {
+		return Err(TestFailureError::new(format!(
+			"too few register writes, the next expected line is \"{}\".",
+			entry,
+		)).with_kind(FailureKind::TooFewWrites));
+	}
+	if let Some(entry) = mem_log.get(mem_id) {
+		return Err(TestFailureError::new(format!(
+			"too few memory writes, the next expected line is \"{}\".",
+			entry,
+		)).with_kind(FailureKind::TooFewWrites));
+	}
+	Ok(())
+}
+
+// Runs a program the way the testbench does, or returns None if the testbench would run it differently.
+fn run_program(opts: &GenOptions, program: &Program) -> Option<MipsMachine> {
+	let mut machine = MipsMachine::new(!opts.no_db, !opts.no_exc, opts.mem_size, &opts.handler);
+	for &code in &program.code {
+		machine.push_text(code);
+	}
+	let mut irqs = program.irqs.clone();
+	if !machine.run_with_irqs(&mut irqs, shrink::MAX_STEPS) {
+		return None;
+	}
+	// The testbench asserts every interrupt it reaches, so all of them must have been taken.
+	if program.irqs.difference(&irqs).any(|addr| !machine.irq_log().contains(addr)) {
+		return None;
+	}
+	if !opts.no_exc {
+		if (machine::TEXT_START_ADDR..machine::TEXT_END_ADDR).contains(&machine.pc()) {
+			return None;
+		}
+		machine.force_exception(machine.pc(), 4);
 	}
+	Some(machine)
+}
+
+// Shrinks a failed test, keeping only trials that fail the same way so that it can't drift to another failure.
+fn shrink_test(opts: &GenOptions, program: Program, kind: Option<FailureKind>, subject_path: &Path, dir: &Path,
+	handler_code: &[u8]) -> io::Result<usize> {
+	let work_dir = tempfile::Builder::new().prefix("shrink-").tempdir_in(dir)?;
+	let work_path = work_dir.path();
+	let mut subject = subject_command(subject_path, work_path)?;
+	std::fs::write(work_path.join("code_handler.txt"), handler_code)?;
+	let tail_len = if opts.no_exc { 0 } else { 2 };
+	let program = shrink::shrink(program, tail_len, |program| {
+		let machine = match run_program(opts, program) {
+			Some(machine) => machine,
+			None => return false,
+		};
+		let mut code_data = Vec::new();
+		for code in &program.code {
+			code_data.extend(format!("{:08x}\n", code).as_bytes());
+		}
+		let is_written = std::fs::write(work_path.join("code.txt"), &code_data).is_ok()
+			&& std::fs::write(work_path.join("irqs.txt"), irq_log_data(&program.irqs, program.code.len())).is_ok();
+		is_written && subject.output().is_ok_and(|subject_res| {
+			check_subject_output(machine.grf_log(), machine.mem_log(), &subject_res).is_err_and(|e| e.kind == kind)
+		})
+	});
+
+	let machine = run_program(opts, &program)
+		.ok_or_else(|| io::Error::other("the failing program does not run on the model"))?;
+	let mut asm_data = Vec::new();
+	let mut code_data = Vec::new();
+	for &code in &program.code {
+		let instr = decode_instruction(code).unwrap_or_else(|_| Box::new(ReservedInstr { code }));
+		asm_data.extend(format!("{}\n", instr).as_bytes());
+		code_data.extend(format!("{:08x}\n", code).as_bytes());
+	}
+	let (grf_log_data, mem_log_data) = log_data(&machine);
+	std::fs::write(dir.join("min.asm"), &asm_data)?;
+	std::fs::write(dir.join("min-code.txt"), &code_data)?;
+	std::fs::write(dir.join("min-irqs.txt"), irq_log_data(&program.irqs, program.code.len()))?;
+	std::fs::write(dir.join("min-std-grf.log"), &grf_log_data)?;
+	std::fs::write(dir.join("min-std-mem.log"), &mem_log_data)?;
+	Ok(program.code.len())
+}
+
+async fn save_failure(dir: &Path, case_dir: &Path, seed: u64, e: &TestFailureError) -> io::Result<()> {
+	tokio::fs::create_dir_all(case_dir).await?;
+	for name in &["code.txt", "code_handler.txt", "irqs.txt", "std-grf.log", "std-mem.log", "test.asm"] {
+		tokio::fs::copy(dir.join(name), case_dir.join(name)).await?;
+	}
+	// Reproducers left by --shrink are saved as well.
+	for name in &["min.asm", "min-code.txt", "min-irqs.txt", "min-std-grf.log", "min-std-mem.log"] {
+		if dir.join(name).exists() {
+			tokio::fs::copy(dir.join(name), case_dir.join(name)).await?;
+		}
+	}
+	let command = std::env::args().collect::<Vec<_>>().join(" ");
+	let meta = format!("seed: {}\ncommand: {}\nreason: {}\n", seed, command, e.reason);
+	tokio::fs::write(case_dir.join("meta.txt"), meta).await
+}
+
+fn parse_handler(data: &str) -> Result<Vec<u32>, Box<dyn Error>> {
+	let handler = if let Ok(handler) = parse_machine_code(data) {
+		handler
+	} else {
+		let program = asm::assemble(data)?;
+		if program.ktext.instrs.is_empty() || program.ktext.addr != machine::HANDLER_ADDR {
+			Err(TestFailureError::new(format!("the exception handler must be in .ktext 0x{:x}", machine::HANDLER_ADDR)))?;
+		}
+		program.ktext.machine_code()
+	};
+	check_machine_code(&handler)?;
+	Ok(handler)
 }
 
 #[tokio::main]
@@ -88,6 +422,10 @@ async fn main() -> Result<(), Box<dyn Error>> {
 			.long("no-exc")
 			.global(true)
 			.help("Disable exception handling and ignore problematic instructions."))
+		.arg(clap::Arg::with_name("timer")
+			.long("timer")
+			.global(true)
+			.help("Generate accesses to the timers, which count executed instructions instead of clock cycles."))
 		.arg(clap::Arg::with_name("mem-size")
 			.long("mem-size")
 			.takes_value(true)
@@ -100,6 +438,31 @@ async fn main() -> Result<(), Box<dyn Error>> {
 			.global(true)
 			.default_value("1118")
 			.help("Number of instructions to generate per test case."))
+		.arg(clap::Arg::with_name("loop-bound")
+			.long("loop-bound")
+			.takes_value(true)
+			.global(true)
+			.default_value("8")
+			.help("Maximum iteration count of generated loops, 0 disables loops."))
+		.arg(clap::Arg::with_name("subroutines")
+			.long("subroutines")
+			.global(true)
+			.help("Generate subroutines with a stack frame, called with jal and returning with jr $ra."))
+		.arg(clap::Arg::with_name("handler")
+			.long("handler")
+			.takes_value(true)
+			.global(true)
+			.help("Path to the exception handler, either assembly with a .ktext section or hex machine code."))
+		.arg(clap::Arg::with_name("profile")
+			.long("profile")
+			.takes_value(true)
+			.global(true)
+			.help("Path to a TOML profile, or JSON if it ends in .json, tuning the generator's probabilities and instruction weights."))
+		.arg(clap::Arg::with_name("seed")
+			.long("seed")
+			.takes_value(true)
+			.global(true)
+			.help("Seed of the generator. test derives the seed of each test from it, replay uses it as is."))
 		.subcommand(clap::SubCommand::with_name("test")
 			.about("Test a given subject.")
 			.arg(clap::Arg::with_name("count")
@@ -117,6 +480,20 @@ async fn main() -> Result<(), Box<dyn Error>> {
 			.arg(clap::Arg::with_name("fail-fast")
 				.long("fail-fast")
 				.help("Stop testing immediately if one test fails."))
+			.arg(clap::Arg::with_name("shrink")
+				.long("shrink")
+				.help("Shrink each failed test to a minimal failing program, written to min.asm and min-code.txt."))
+			.arg(clap::Arg::with_name("coverage")
+				.long("coverage")
+				.help("Print what the generated tests exercised on the model, aggregated across all tests."))
+			.arg(clap::Arg::with_name("feedback")
+				.long("feedback")
+				.help("Steer generation toward dependencies earlier tests have not exercised. Tests then depend on the ones before them, so they cannot be replayed from their seed alone."))
+			.arg(clap::Arg::with_name("save-failures")
+				.long("save-failures")
+				.value_name("DIR")
+				.takes_value(true)
+				.help("Save each failed test to a subdirectory of DIR named after its seed, for use with regress."))
 			.arg(clap::Arg::with_name("tmp-dir")
 				.short("d")
 				.long("tmp-dir")
@@ -128,6 +505,66 @@ async fn main() -> Result<(), Box<dyn Error>> {
 				.value_name("TEST_SUBJECT")
 				.required(true)
 				.help("Path to the compiled output of iverilog to be tested.")))
+		.subcommand(clap::SubCommand::with_name("regress")
+			.about("Rerun the failed tests saved by --save-failures and report which of them are fixed.")
+			.arg(clap::Arg::with_name("threads")
+				.short("t")
+				.long("threads")
+				.takes_value(true)
+				.default_value(&default_threads)
+				.help("Number of threads used to run the tests in parallel."))
+			.arg(clap::Arg::with_name("save-dir")
+				.index(1)
+				.value_name("DIR")
+				.required(true)
+				.help("Directory the failed tests were saved to."))
+			.arg(clap::Arg::with_name("subject-path")
+				.index(2)
+				.value_name("TEST_SUBJECT")
+				.required(true)
+				.help("Path to the compiled output of iverilog to be tested.")))
+		.subcommand(clap::SubCommand::with_name("replay")
+			.about("Regenerate a failed test from its seed, given the same options it was run with.")
+			.arg(clap::Arg::with_name("output-dir")
+				.short("o")
+				.long("output-dir")
+				.takes_value(true)
+				.default_value(".")
+				.help("Directory to write the code, the irq schedule and the standard logs to.")))
+		.subcommand(clap::SubCommand::with_name("run")
+			.about("Run existing machine code on the reference model and write standard logs.")
+			.arg(clap::Arg::with_name("max-steps")
+				.long("max-steps")
+				.takes_value(true)
+				.default_value("1000000")
+				.help("Maximum number of instructions to execute."))
+			.arg(clap::Arg::with_name("output-dir")
+				.short("o")
+				.long("output-dir")
+				.takes_value(true)
+				.default_value(".")
+				.help("Directory to write std-grf.log and std-mem.log to."))
+			.arg(clap::Arg::with_name("code-path")
+				.index(1)
+				.value_name("CODE")
+				.required(true)
+				.help("Path to the hex machine code file, e.g. code.txt."))
+			.arg(clap::Arg::with_name("handler-path")
+				.index(2)
+				.value_name("HANDLER")
+				.help("Path to the exception handler, e.g. code_handler.txt. Overrides --handler.")))
+		.subcommand(clap::SubCommand::with_name("asm")
+			.about("Assemble a source file into hex machine code.")
+			.arg(clap::Arg::with_name("source-path")
+				.index(1)
+				.value_name("SOURCE")
+				.required(true)
+				.help("Path to the assembly source file."))
+			.arg(clap::Arg::with_name("output-name")
+				.index(2)
+				.value_name("OUTPUT")
+				.required(true)
+				.help("Name of output code file, the exception handler is written to OUTPUT_handler.txt.")))
 		.subcommand(clap::SubCommand::with_name("gen")
 			.about("Generate test data.")
 			.arg(clap::Arg::with_name("output-name")
@@ -138,10 +575,17 @@ async fn main() -> Result<(), Box<dyn Error>> {
 		.get_matches();
 	let no_db = matches.is_present("no-db");
 	let no_exc = matches.is_present("no-exc");
+	let timer = matches.is_present("timer");
+	let subroutines = matches.is_present("subroutines");
 	let mem_size = matches.value_of("mem-size").unwrap().parse::<usize>()?;
+	if mem_size > machine::MAX_MEM_SIZE {
+		Err(TestFailureError::new(format!("--mem-size must be at most {} to stay below the timers", machine::MAX_MEM_SIZE)))?;
+	}
 	let instr_count = matches.value_of("instr-count").unwrap().parse::<u32>()?;
+	let loop_bound = matches.value_of("loop-bound").unwrap().parse::<u16>()?;
+	let seed = matches.value_of("seed").map(str::parse::<u64>).transpose()?;
 	let instr_set = if let Some(only_instr) = matches.value_of("only-instr") {
-		only_instr.split(',').map(|s| InstructionType::from_str(s)).collect::<Result<Vec<_>, _>>()?
+		only_instr.split(',').map(InstructionType::from_str).collect::<Result<Vec<_>, _>>()?
 	} else {
 		let excluded = matches.value_of("exclude-instr")
 			.unwrap_or("")
@@ -151,13 +595,48 @@ async fn main() -> Result<(), Box<dyn Error>> {
 			!excluded.contains(instr.as_static())
 		}).collect::<Vec<_>>()
 	};
+	if subroutines {
+		let required = [InstructionType::Ori, InstructionType::Addiu, InstructionType::Lw, InstructionType::Sw,
+			InstructionType::J, InstructionType::Jal, InstructionType::Jr];
+		if let Some(instr) = required.iter().find(|x| !instr_set.contains(x)) {
+			Err(TestFailureError::new(format!("{} is required to generate subroutines", instr.as_static())))?;
+		}
+	}
 	let instr_set = Arc::new(instr_set);
+	let handler = if let Some(handler_path) = matches.value_of_os("handler") {
+		parse_handler(&tokio::fs::read_to_string(handler_path).await?)?
+	} else {
+		HANDLER.clone()
+	};
+	let handler = Arc::new(handler);
+	let profile = if let Some(profile_path) = matches.value_of_os("profile") {
+		let is_json = Path::new(profile_path).extension().is_some_and(|x| x == "json");
+		Profile::parse(&tokio::fs::read_to_string(profile_path).await?, is_json)?
+	} else {
+		Profile::default()
+	};
+	let profile = Arc::new(profile);
+	let mut handler_code = Vec::new();
+	for code in handler.iter() {
+		handler_code.extend(format!("{:08x}\n", code).as_bytes());
+	}
+	let opts = GenOptions {
+		no_db, no_exc, mem_size, instr_count, timer, loop_bound, subroutines,
+		coverage: false,
+		instr_set: Arc::clone(&instr_set),
+		handler: Arc::clone(&handler),
+		profile: Arc::clone(&profile),
+	};
 
 	match matches.subcommand() {
 		("test", Some(matches)) => {
 			let test_count = matches.value_of("count").unwrap().parse::<u32>()?;
 			let thread_count = matches.value_of("threads").unwrap().parse::<usize>()?;
 			let fail_fast = matches.is_present("fail-fast");
+			let shrink = matches.is_present("shrink");
+			let save_dir = matches.value_of_os("save-failures");
+			let opts = GenOptions { coverage: matches.is_present("coverage"), ..opts };
+			let feedback = matches.is_present("feedback");
 			let tmp_dir = matches.value_of_os("tmp-dir").unwrap();
 			let subject_path = matches.value_of_os("subject-path").unwrap();
 
@@ -166,118 +645,54 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
 			let (cancel_tx, cancel_rx) = oneshot::channel();
 			let cancel_tx = RefCell::new(Some(cancel_tx));
+			let coverage = RefCell::new(Coverage::default());
 
-			let fut = stream::iter(0..test_count).for_each_concurrent(thread_count, |_| async {
-				let instr_set = Arc::clone(&instr_set);
+			let mut seed_rng = StdRng::seed_from_u64(seed.unwrap_or_else(random));
+			let seeds = (0..test_count).map(|_| seed_rng.gen::<u64>()).collect::<Vec<_>>();
+			let (opts, handler_code) = (&opts, &handler_code);
+			let (success_count, failure_count, cancel_tx, coverage) = (&success_count, &failure_count, &cancel_tx, &coverage);
+			let fut = stream::iter(seeds).for_each_concurrent(thread_count, |seed| async move {
 				let dir = tempfile::Builder::new().prefix("co-tester-").tempdir_in(tmp_dir).unwrap();
 				let dir_path = dir.path();
-				let (asm_data, code_data, grf_log_data, mem_log_data, irq_log_data, machine) =
-					tokio::task::spawn_blocking(move || {
-						let mut asm_data = Vec::new();
-						let mut code_data = Vec::new();
-						let mut machine = MipsMachine::new(!no_db, !no_exc, mem_size);
-						for instr in InstructionGenerator::new(&mut machine, &instr_set, instr_count) {
-							asm_data.extend(format!("{}\n", instr).as_bytes());
-							code_data.extend(format!("{:08x}\n", instr.to_machine_code()).as_bytes());
-						}
-						if !no_exc {
-							machine.force_exception(0x10000, 4);
-							let instr = JInstr { addr: 16384 };
-							asm_data.extend(format!("{}\nnop\n", instr).as_bytes());
-							code_data.extend(format!("{:08x}\n00000000\n", instr.to_machine_code()).as_bytes());
-						}
-						let mut grf_log_data = Vec::new();
-						for log in machine.grf_log() {
-							grf_log_data.extend(format!("{}\n", log).as_bytes());
-						}
-						let mut mem_log_data = Vec::new();
-						for log in machine.mem_log() {
-							mem_log_data.extend(format!("{}\n", log).as_bytes());
-						}
-						let mut irq_log_data = Vec::new();
-						for i in 0..instr_count {
-							let addr = i * machine::WORD_SIZE as u32 + machine::TEXT_START_ADDR;
-							let flag = if machine.irq_log().contains(&addr) { 1 } else { 0 };
-							irq_log_data.extend(format!("{}\n", flag).as_bytes());
-						}
-						(asm_data, code_data, grf_log_data, mem_log_data, irq_log_data, machine)
-					}).await.unwrap();
-				File::create(dir_path.join("test.asm")).await.unwrap().write_all(&asm_data).await.unwrap();
-				File::create(dir_path.join("code.txt")).await.unwrap().write_all(&code_data).await.unwrap();
-				File::create(dir_path.join("code_handler.txt")).await.unwrap().write_all(HANDLER_CODE).await.unwrap();
-				File::create(dir_path.join("irqs.txt")).await.unwrap().write_all(&irq_log_data).await.unwrap();
-				File::create(dir_path.join("std-grf.log")).await.unwrap().write_all(&grf_log_data).await.unwrap();
-				File::create(dir_path.join("std-mem.log")).await.unwrap().write_all(&mem_log_data).await.unwrap();
-				let subject_res = Command::new(std::fs::canonicalize(subject_path).unwrap())
-					.current_dir(dir_path)
-					.stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::piped())
+				let test = {
+					let opts = opts.clone();
+					let seen_hazards = if feedback { Some(*coverage.borrow().hazards()) } else { None };
+					tokio::task::spawn_blocking(move || gen_test(&opts, seed, seen_hazards)).await.unwrap()
+				};
+				write_test(dir_path, &test, handler_code).await.unwrap();
+				if let Some(test_coverage) = test.machine.coverage() {
+					coverage.borrow_mut().merge(test_coverage);
+				}
+				let program = Program { code: test.code, irqs: test.machine.irq_log().clone() };
+				let machine = test.machine;
+				let subject_res = Command::from(subject_command(Path::new(subject_path), dir_path).unwrap())
 					.output().await.unwrap();
-				File::create(dir_path.join("subject.log")).await.unwrap().write_all(&subject_res.stdout).await.unwrap();
+				tokio::fs::write(dir_path.join("subject.log"), &subject_res.stdout).await.unwrap();
 				let res = tokio::task::spawn_blocking(move || {
-					let subject_log = String::from_utf8_lossy(&subject_res.stdout);
-					if !subject_res.status.success() {
-						return Err(TestFailureError::new(format!(
-							"failed to run the test subject.\nstdout:\n{}\nstderr:\n{}",
-							subject_log, String::from_utf8_lossy(&subject_res.stderr),
-						)));
-					}
-					let mut grf_id = 0;
-					let mut mem_id = 0;
-					for (i, subject_line) in subject_log.lines().enumerate() {
-						match subject_line.parse::<LogEntry>() {
-							Ok(LogEntry::Grf(grf_entry)) => {
-								if grf_entry.addr() == 0 { continue; }
-								if let Some(std_entry) = machine.grf_log().get(grf_id) {
-									if grf_entry != *std_entry {
-										return Err(TestFailureError::new(format!(
-											"got \"{}\" at line {}, but expected \"{}\"",
-											grf_entry, i + 1, std_entry,
-										)));
-									}
-									grf_id += 1;
-								} else {
-									return Err(TestFailureError::new(format!(
-										"got \"{}\" at line {}, but standard output has ended.",
-										grf_entry, i + 1,
-									)));
-								}
-							}
-							Ok(LogEntry::Mem(mem_entry)) => {
-								if let Some(std_entry) = machine.mem_log().get(mem_id) {
-									if mem_entry != *std_entry {
-										return Err(TestFailureError::new(format!(
-											"got \"{}\" at line {}, but expected \"{}\"",
-											mem_entry, i + 1, std_entry,
-										)));
-									}
-									mem_id += 1;
-								} else {
-									return Err(TestFailureError::new(format!(
-										"got \"{}\" at line {}, but standard output has ended.",
-										mem_entry, i + 1,
-									)));
-								}
-							}
-							Err(_) => (),
-						}
-					}
-					if let Some(entry) = machine.grf_log().get(grf_id) {
-						return Err(TestFailureError::new(format!(
-							"too few register writes, the next expected line is \"{}\".",
-							entry,
-						)));
-					}
-					if let Some(entry) = machine.mem_log().get(mem_id) {
-						return Err(TestFailureError::new(format!(
-							"too few memory writes, the next expected line is \"{}\".",
-							entry,
-						)));
-					}
-					Ok(())
+					check_subject_output(machine.grf_log(), machine.mem_log(), &subject_res)
 				}).await.unwrap();
 				if let Err(e) = res {
+					let e = e.with_seed(seed);
 					println!("{}", e);
-					println!("Relevant files are in {}\n", dir.into_path().to_string_lossy());
+					let dir_path = dir.into_path();
+					if shrink {
+						let (opts, subject_path, dir_path) = (opts.clone(), PathBuf::from(subject_path), dir_path.clone());
+						let (handler_code, kind) = (handler_code.clone(), e.kind);
+						let res = tokio::task::spawn_blocking(move || {
+							shrink_test(&opts, program, kind, &subject_path, &dir_path, &handler_code)
+						}).await.unwrap();
+						match res {
+							Ok(len) => println!("Shrunk to {} instructions in min.asm", len),
+							Err(e) => println!("Failed to shrink the test: {}", e),
+						}
+					}
+					if let Some(save_dir) = save_dir {
+						let case_dir = Path::new(save_dir).join(seed.to_string());
+						if let Err(e) = save_failure(&dir_path, &case_dir, seed, &e).await {
+							println!("Failed to save the test: {}", e);
+						}
+					}
+					println!("Relevant files are in {}\n", dir_path.to_string_lossy());
 					failure_count.fetch_add(1, Ordering::Relaxed);
 					if fail_fast {
 						if let Some(cancel_tx) = cancel_tx.borrow_mut().take() {
@@ -315,6 +730,126 @@ async fn main() -> Result<(), Box<dyn Error>> {
 				failure_count,
 				test_count - success_count - failure_count,
 			);
+			if opts.coverage {
+				println!("\n{}", coverage.borrow());
+			}
+		},
+		("regress", Some(matches)) => {
+			let thread_count = matches.value_of("threads").unwrap().parse::<usize>()?;
+			let save_dir = Path::new(matches.value_of_os("save-dir").unwrap());
+			let subject_path = Path::new(matches.value_of_os("subject-path").unwrap());
+			let mut case_dirs = Vec::new();
+			let mut entries = tokio::fs::read_dir(save_dir).await?;
+			while let Some(entry) = entries.next_entry().await? {
+				if entry.file_type().await?.is_dir() {
+					case_dirs.push(entry.path());
+				}
+			}
+			case_dirs.sort();
+
+			let results = stream::iter(&case_dirs)
+				.map(|case_dir| async move {
+					let dir = tempfile::Builder::new().prefix("co-tester-").tempdir()?;
+					for name in &["code.txt", "code_handler.txt", "irqs.txt"] {
+						tokio::fs::copy(case_dir.join(name), dir.path().join(name)).await?;
+					}
+					let mut std_log = tokio::fs::read_to_string(case_dir.join("std-grf.log")).await?;
+					std_log += &tokio::fs::read_to_string(case_dir.join("std-mem.log")).await?;
+					let (grf_log, mem_log) = parse_log(&std_log)?;
+					let subject_res = Command::from(subject_command(subject_path, dir.path())?).output().await?;
+					Ok::<_, Box<dyn Error>>(check_subject_output(&grf_log, &mem_log, &subject_res))
+				})
+				.buffered(thread_count)
+				.collect::<Vec<_>>().await;
+			let mut failure_count = 0;
+			let mut error_count = 0;
+			for (case_dir, res) in case_dirs.iter().zip(results) {
+				let name = case_dir.file_name().unwrap().to_string_lossy();
+				match res {
+					Ok(Ok(())) => println!("{} is fixed", name),
+					Ok(Err(e)) => {
+						println!("{} still fails: {}", name, e.reason);
+						failure_count += 1;
+					}
+					Err(e) => {
+						println!("{} could not be run: {}", name, e);
+						error_count += 1;
+					}
+				}
+			}
+			println!(
+				"{} fixed, {} still failing, {} could not be run",
+				case_dirs.len() - failure_count - error_count, failure_count, error_count,
+			);
+		},
+		("replay", Some(matches)) => {
+			let seed = seed.ok_or_else(|| TestFailureError::new(String::from("replay requires --seed")))?;
+			let output_dir = Path::new(matches.value_of_os("output-dir").unwrap());
+			let test = tokio::task::spawn_blocking(move || gen_test(&opts, seed, None)).await?;
+			write_test(output_dir, &test, &handler_code).await?;
+		},
+		("run", Some(matches)) => {
+			let max_steps = matches.value_of("max-steps").unwrap().parse::<usize>()?;
+			let output_dir = Path::new(matches.value_of_os("output-dir").unwrap());
+			let code = parse_machine_code(&tokio::fs::read_to_string(matches.value_of_os("code-path").unwrap()).await?)?;
+			let handler = if let Some(handler_path) = matches.value_of_os("handler-path") {
+				parse_handler(&tokio::fs::read_to_string(handler_path).await?)?
+			} else {
+				handler.to_vec()
+			};
+			if no_exc {
+				check_machine_code(&code)?;
+			}
+
+			let (grf_log_data, mem_log_data, finished, branch_in_delay_slot) = tokio::task::spawn_blocking(move || {
+				let mut machine = MipsMachine::new(!no_db, !no_exc, mem_size, &handler);
+				for code in code {
+					machine.push_text(code);
+				}
+				let finished = machine.run_with_irqs(&mut HashSet::new(), max_steps);
+				let branch_in_delay_slot = Some(machine.pc()).filter(|_| machine.is_at_branch_in_delay_slot());
+				if finished && !no_exc && !(machine::TEXT_START_ADDR..machine::TEXT_END_ADDR).contains(&machine.pc()) {
+					// Generated tests end with a jump out of the address space, and the handler runs once there.
+					machine.force_exception(machine.pc(), 4);
+				}
+				let (grf_log_data, mem_log_data) = log_data(&machine);
+				(grf_log_data, mem_log_data, finished, branch_in_delay_slot)
+			}).await?;
+			tokio::fs::write(output_dir.join("std-grf.log"), &grf_log_data).await?;
+			tokio::fs::write(output_dir.join("std-mem.log"), &mem_log_data).await?;
+			if let Some(pc) = branch_in_delay_slot {
+				Err(TestFailureError::new(format!("unpredictable branch in the delay slot at 0x{:08x}", pc)))?;
+			} else if !finished {
+				println!("Execution stopped after {} steps without leaving the program.", max_steps);
+			}
+		},
+		("asm", Some(matches)) => {
+			let output_name = matches.value_of_os("output-name").unwrap();
+			let mut code_path = output_name.to_owned();
+			code_path.push(".txt");
+			let mut handler_path = output_name.to_owned();
+			handler_path.push("_handler.txt");
+
+			let src = tokio::fs::read_to_string(matches.value_of_os("source-path").unwrap()).await?;
+			let program = asm::assemble(&src)?;
+			if program.text.addr != machine::TEXT_START_ADDR || program.ktext.addr != machine::HANDLER_ADDR {
+				Err(TestFailureError::new(format!(
+					".text and .ktext must start at 0x{:x} and 0x{:x}",
+					machine::TEXT_START_ADDR, machine::HANDLER_ADDR,
+				)))?;
+			}
+			let mut code_data = Vec::new();
+			for code in program.text.machine_code() {
+				code_data.extend(format!("{:08x}\n", code).as_bytes());
+			}
+			tokio::fs::write(&code_path, &code_data).await?;
+			if !program.ktext.instrs.is_empty() {
+				let mut handler_data = Vec::new();
+				for code in program.ktext.machine_code() {
+					handler_data.extend(format!("{:08x}\n", code).as_bytes());
+				}
+				tokio::fs::write(&handler_path, &handler_data).await?;
+			}
 		},
 		("gen", Some(matches)) => {
 			let output_name = matches.value_of_os("output-name").unwrap();
@@ -330,15 +865,17 @@ async fn main() -> Result<(), Box<dyn Error>> {
 			let (asm_data, code_data) = tokio::task::spawn_blocking(move || {
 				let mut asm_data = Vec::new();
 				let mut code_data = Vec::new();
-				let mut machine = MipsMachine::new(!no_db, !no_exc, mem_size);
-				for instr in InstructionGenerator::new(&mut machine, &instr_set, instr_count) {
+				let mut machine = MipsMachine::new(!no_db, !no_exc, mem_size, &handler);
+				let seed = seed.unwrap_or_else(random);
+				for instr in InstructionGenerator::new(&mut machine, &instr_set, instr_count, timer, loop_bound, subroutines, seed)
+					.with_profile(&profile) {
 					asm_data.extend(format!("{}\n", instr).as_bytes());
 					code_data.extend(format!("{:08x}\n", instr.to_machine_code()).as_bytes());
 				}
 				(asm_data, code_data)
 			}).await?;
-			File::create(&asm_path).await?.write_all(&asm_data).await?;
-			File::create(&code_path).await?.write_all(&code_data).await?;
+			tokio::fs::write(&asm_path, &asm_data).await?;
+			tokio::fs::write(&code_path, &code_data).await?;
 		},
 		_ => (),
 	}
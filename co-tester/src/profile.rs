@@ -0,0 +1,170 @@
+use rand::Rng;
+use rand::distributions::WeightedIndex;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+use strum::IntoEnumIterator;
+use super::gen::InstructionType;
+
+#[derive(Debug)]
+pub struct InvalidProfileError {
+	reason: String,
+}
+
+impl Display for InvalidProfileError {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		write!(f, "invalid profile: {}", self.reason)
+	}
+}
+
+impl Error for InvalidProfileError {}
+
+// Tunable knobs of the generator. Instructions missing from `weights` have weight 1, and those with weight 0 are
+// only picked when nothing else could be.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Profile {
+	pub grf_reuse_prob: f64,
+	pub mem_reuse_prob: f64,
+	pub unaligned_prob: f64,
+	pub irq_prob: f64,
+	pub unaligned_jr_prob: f64,
+	pub branch_dist_std_dev: f64,
+	pub edge_value_prob: f64,
+	pub edge_imm_prob: f64,
+	pub addr_setup_prob: f64,
+	pub loop_prob: f64,
+	pub subroutine_prob: f64,
+	pub call_prob: f64,
+	pub nested_call_prob: f64,
+	pub accumulate_prob: f64,
+	pub ll_sc_prob: f64,
+	pub ll_sc_irq_prob: f64,
+	pub feedback_prob: f64,
+	pub timer_access_prob: f64,
+	pub zero_cond_prob: f64,
+	pub trap_equal_prob: f64,
+	pub zero_code_field_prob: f64,
+	pub reserved_near_miss_prob: f64,
+	pub mem_setup_prob: f64,
+	pub addiu_setup_prob: f64,
+	weights: BTreeMap<String, f64>,
+	#[serde(skip)]
+	type_weights: Vec<f64>,
+}
+
+impl Default for Profile {
+	fn default() -> Self {
+		Self {
+			grf_reuse_prob: 0.5,
+			mem_reuse_prob: 0.3,
+			unaligned_prob: 0.2,
+			irq_prob: 0.1,
+			unaligned_jr_prob: 0.1,
+			branch_dist_std_dev: 5.0,
+			edge_value_prob: 0.02,
+			edge_imm_prob: 0.2,
+			addr_setup_prob: 0.03,
+			loop_prob: 0.02,
+			subroutine_prob: 0.01,
+			call_prob: 0.03,
+			nested_call_prob: 0.2,
+			accumulate_prob: 0.5,
+			ll_sc_prob: 0.02,
+			ll_sc_irq_prob: 0.3,
+			feedback_prob: 0.5,
+			timer_access_prob: 0.2,
+			zero_cond_prob: 0.5,
+			trap_equal_prob: 0.5,
+			zero_code_field_prob: 0.5,
+			reserved_near_miss_prob: 0.5,
+			mem_setup_prob: 0.5,
+			addiu_setup_prob: 0.5,
+			weights: BTreeMap::new(),
+			type_weights: vec![1.0; InstructionType::iter().count()],
+		}
+	}
+}
+
+impl Profile {
+	// Parses a TOML profile, or a JSON one if `is_json` is set.
+	pub fn parse(data: &str, is_json: bool) -> Result<Self, Box<dyn Error>> {
+		let mut profile: Self = if is_json { serde_json::from_str(data)? } else { toml::from_str(data)? };
+		let probs = [
+			("grf-reuse-prob", profile.grf_reuse_prob),
+			("mem-reuse-prob", profile.mem_reuse_prob),
+			("unaligned-prob", profile.unaligned_prob),
+			("irq-prob", profile.irq_prob),
+			("unaligned-jr-prob", profile.unaligned_jr_prob),
+			("edge-value-prob", profile.edge_value_prob),
+			("edge-imm-prob", profile.edge_imm_prob),
+			("addr-setup-prob", profile.addr_setup_prob),
+			("loop-prob", profile.loop_prob),
+			("subroutine-prob", profile.subroutine_prob),
+			("call-prob", profile.call_prob),
+			("nested-call-prob", profile.nested_call_prob),
+			("accumulate-prob", profile.accumulate_prob),
+			("ll-sc-prob", profile.ll_sc_prob),
+			("ll-sc-irq-prob", profile.ll_sc_irq_prob),
+			("feedback-prob", profile.feedback_prob),
+			("timer-access-prob", profile.timer_access_prob),
+			("zero-cond-prob", profile.zero_cond_prob),
+			("trap-equal-prob", profile.trap_equal_prob),
+			("zero-code-field-prob", profile.zero_code_field_prob),
+			("reserved-near-miss-prob", profile.reserved_near_miss_prob),
+			("mem-setup-prob", profile.mem_setup_prob),
+			("addiu-setup-prob", profile.addiu_setup_prob),
+		];
+		if let Some((name, _)) = probs.iter().find(|(_, x)| !(0.0..=1.0).contains(x)) {
+			Err(InvalidProfileError { reason: format!("{} must be between 0 and 1", name) })?;
+		}
+		if !(profile.branch_dist_std_dev.is_finite() && profile.branch_dist_std_dev >= 0.0) {
+			Err(InvalidProfileError { reason: String::from("branch-dist-std-dev must be a non-negative number") })?;
+		}
+		for (mnemonic, weight) in &profile.weights {
+			let instr = InstructionType::from_str(mnemonic)
+				.map_err(|_| InvalidProfileError { reason: format!("unknown instruction {}", mnemonic) })?;
+			if !(weight.is_finite() && *weight >= 0.0) {
+				Err(InvalidProfileError { reason: format!("the weight of {} must be a non-negative number", mnemonic) })?;
+			}
+			profile.type_weights[instr as usize] = *weight;
+		}
+		Ok(profile)
+	}
+
+	pub fn weight(&self, instr: InstructionType) -> f64 {
+		self.type_weights[instr as usize]
+	}
+
+	// Picks an instruction type by weight, or uniformly if none of them has any.
+	pub fn select<R: Rng>(&self, rng: &mut R, types: &[InstructionType]) -> InstructionType {
+		match WeightedIndex::new(types.iter().map(|x| self.weight(*x))) {
+			Ok(dist) => types[rng.sample(dist)],
+			Err(_) => types[rng.gen_range(0..types.len())],
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn parse_profile() {
+		let profile = Profile::parse("irq-prob = 0.3\n[weights]\nlw = 4\nbeq = 0\n", false).unwrap();
+		assert_eq!(profile.irq_prob, 0.3);
+		assert_eq!(profile.grf_reuse_prob, 0.5);
+		assert_eq!(profile.weight(InstructionType::Lw), 4.0);
+		assert_eq!(profile.weight(InstructionType::Add), 1.0);
+		let json = Profile::parse(r#"{"branch-dist-std-dev": 2, "weights": {"beq": 0}}"#, true).unwrap();
+		assert_eq!(json.branch_dist_std_dev, 2.0);
+		assert_eq!(json.select(&mut rand::thread_rng(), &[InstructionType::Beq, InstructionType::Bne]), InstructionType::Bne);
+		assert!(Profile::parse("irq-prob = 1.5\n", false).is_err());
+		assert!(Profile::parse("[weights]\nfoo = 1\n", false).is_err());
+		assert!(Profile::parse("irq = 0.1\n", false).is_err());
+		let default = Profile::parse(include_str!("../profiles/default.toml"), false).unwrap();
+		assert_eq!(default.ll_sc_irq_prob, Profile::default().ll_sc_irq_prob);
+	}
+}
@@ -0,0 +1,159 @@
+use std::cmp;
+use std::collections::HashSet;
+use super::machine::*;
+
+pub const MAX_STEPS: usize = 1 << 20;
+
+#[derive(Debug, Clone)]
+pub struct Program {
+	pub code: Vec<u32>,
+	pub irqs: HashSet<u32>,
+}
+
+fn index_addr(index: usize) -> u32 {
+	TEXT_START_ADDR + (index * WORD_SIZE) as u32
+}
+
+fn addr_index(addr: u32, len: usize) -> Option<usize> {
+	if addr < TEXT_START_ADDR || addr & 0b11 != 0 { return None; }
+	let index = ((addr - TEXT_START_ADDR) / WORD_SIZE as u32) as usize;
+	if index <= len { Some(index) } else { None }
+}
+
+fn is_branch(code: u32) -> bool {
+	match code >> 26 {
+		0b000001 => matches!(code >> 16 & 0x1f, 0b00000 | 0b00001 | 0b10000 | 0b10001),
+		0b000100 | 0b000101 | 0b000110 | 0b000111 | 0b010100 | 0b010101 => true,
+		_ => false,
+	}
+}
+
+fn is_jump(code: u32) -> bool {
+	matches!(code >> 26, 0b000010 | 0b000011)
+}
+
+// Deletes the given instructions, moving branch and jump targets and the irq schedule along with the code.
+// Targets of jr and jalr are built in registers and stay where they were, so deleting code before one of them
+// changes which instruction it lands on. Callers must check that a trial still fails the same way.
+fn remove(program: &Program, removed: &[usize]) -> Program {
+	let mut removed = removed.to_vec();
+	removed.sort_unstable();
+	let new_index = |index: usize| index - removed.partition_point(|&x| x < index);
+	let len = program.code.len();
+	let mut code = Vec::new();
+	for (i, &x) in program.code.iter().enumerate() {
+		if removed.binary_search(&i).is_ok() { continue; }
+		let pc = index_addr(i);
+		let new_pc = index_addr(new_index(i));
+		let x = if is_branch(x) {
+			let target = u32::wrapping_add(pc + WORD_SIZE as u32, (x as i16 as i32 * WORD_SIZE as i32) as u32);
+			let target = addr_index(target, len).map_or(target, |t| index_addr(new_index(t)));
+			let offset = (target.wrapping_sub(new_pc + WORD_SIZE as u32) as i32) / WORD_SIZE as i32;
+			x & 0xffff0000 | offset as u16 as u32
+		} else if is_jump(x) {
+			let target = (pc + WORD_SIZE as u32) & 0xf0000000 | (x & 0x3ffffff) << 2;
+			let target = addr_index(target, len).map_or(target, |t| index_addr(new_index(t)));
+			x & 0xfc000000 | (target >> 2 & 0x3ffffff)
+		} else {
+			x
+		};
+		code.push(x);
+	}
+	let irqs = program.irqs.iter()
+		.filter_map(|&addr| match addr_index(addr, len) {
+			Some(i) if removed.binary_search(&i).is_ok() => None,
+			Some(i) => Some(index_addr(new_index(i))),
+			None => Some(addr),
+		})
+		.collect();
+	Program { code, irqs }
+}
+
+// Delta debugging: removes chunks of `items` as long as the failure persists, halving the chunks when none can go.
+fn reduce<F: FnMut(&[usize]) -> bool>(mut items: Vec<usize>, mut still_fails: F) -> Vec<usize> {
+	let mut removed = Vec::new();
+	let mut n = 2;
+	while !items.is_empty() {
+		let chunk_size = items.len().div_ceil(n);
+		let mut is_reduced = false;
+		for start in (0..items.len()).step_by(chunk_size) {
+			let end = cmp::min(start + chunk_size, items.len());
+			let mut trial = removed.clone();
+			trial.extend_from_slice(&items[start..end]);
+			if still_fails(&trial) {
+				removed = trial;
+				items.drain(start..end);
+				n = cmp::max(n - 1, 2);
+				is_reduced = true;
+				break;
+			}
+		}
+		if !is_reduced {
+			if chunk_size == 1 { break; }
+			n = cmp::min(n * 2, items.len());
+		}
+	}
+	removed
+}
+
+// Shrinks a failing program, keeping the last `tail_len` instructions that end it. Interrupts are dropped
+// first, then instructions are replaced with nops, and finally the nops are deleted.
+pub fn shrink<F: FnMut(&Program) -> bool>(program: Program, tail_len: usize, mut still_fails: F) -> Program {
+	let irqs = program.irqs.iter().copied().collect::<Vec<_>>();
+	let dropped = reduce((0..irqs.len()).collect(), |dropped| {
+		let irqs = irqs.iter().enumerate().filter(|(i, _)| !dropped.contains(i)).map(|(_, &x)| x).collect();
+		still_fails(&Program { code: program.code.clone(), irqs })
+	});
+	let irqs = irqs.iter().enumerate().filter(|(i, _)| !dropped.contains(i)).map(|(_, &x)| x).collect();
+	let mut program = Program { code: program.code, irqs };
+
+	let body_len = program.code.len().saturating_sub(tail_len);
+	let items = (0..body_len).filter(|&i| program.code[i] != 0).collect();
+	let nopped = reduce(items, |nopped| {
+		let mut trial = program.clone();
+		for &i in nopped {
+			trial.code[i] = 0;
+		}
+		still_fails(&trial)
+	});
+	for i in nopped {
+		program.code[i] = 0;
+	}
+
+	let items = (0..body_len).filter(|&i| program.code[i] == 0).collect();
+	let removed = reduce(items, |removed| still_fails(&remove(&program, removed)));
+	remove(&program, &removed)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn shrink_keeps_jump_targets() {
+		let code = vec![
+			OriInstr { rs: 0, rt: 1, imm: 5 }.to_machine_code(),
+			JalInstr { addr: index_addr(5) / WORD_SIZE as u32 }.to_machine_code(),
+			NopInstr.to_machine_code(),
+			OriInstr { rs: 0, rt: 2, imm: 6 }.to_machine_code(),
+			OriInstr { rs: 0, rt: 3, imm: 7 }.to_machine_code(),
+			AddInstr { rs: 1, rt: 1, rd: 5 }.to_machine_code(),
+			OriInstr { rs: 0, rt: 6, imm: 8 }.to_machine_code(),
+		];
+		let program = Program { code, irqs: [index_addr(3)].iter().copied().collect() };
+		// The failure to reproduce is $5 ending up as 10 after a call.
+		let shrunk = shrink(program, 0, |program| {
+			let mut machine = MipsMachine::new(true, false, 1024, &[]);
+			for &code in &program.code {
+				machine.push_text(code);
+			}
+			machine.run(1000) && machine.grf()[5] == 10 && machine.grf()[31] != 0
+		});
+		assert!(shrunk.irqs.is_empty());
+		assert_eq!(shrunk.code, vec![
+			OriInstr { rs: 0, rt: 1, imm: 5 }.to_machine_code(),
+			JalInstr { addr: index_addr(2) / WORD_SIZE as u32 }.to_machine_code(),
+			AddInstr { rs: 1, rt: 1, rd: 5 }.to_machine_code(),
+		]);
+	}
+}
@@ -0,0 +1,101 @@
+use super::machine::Device;
+
+pub const TIMER_LEN: u32 = 12;
+pub const TIMER_CTRL: u32 = 0;
+pub const TIMER_PRESET: u32 = 4;
+pub const TIMER_COUNT: u32 = 8;
+
+const CTRL_ENABLE: u32 = 1 << 0;
+const CTRL_MODE: u32 = 0b11 << 1;
+const CTRL_IM: u32 = 1 << 3;
+const CTRL_MASK: u32 = 0b1111;
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Default)]
+enum TimerState {
+	#[default]
+	Idle,
+	Load,
+	Count,
+	Int,
+}
+
+// Mirrors TC.v, where one call to tick() is one clock cycle.
+#[derive(Debug, Clone, Default)]
+pub struct Timer {
+	state: TimerState,
+	ctrl: u32,
+	preset: u32,
+	count: u32,
+	irq: bool,
+	written: bool,
+}
+
+impl Device for Timer {
+	fn size(&self) -> u32 {
+		TIMER_LEN
+	}
+
+	fn read(&self, offset: u32) -> u32 {
+		match offset {
+			TIMER_CTRL => self.ctrl,
+			TIMER_PRESET => self.preset,
+			TIMER_COUNT => self.count,
+			_ => 0,
+		}
+	}
+
+	fn write(&mut self, offset: u32, data: u32) {
+		match offset {
+			TIMER_CTRL => self.ctrl = data & CTRL_MASK,
+			TIMER_PRESET => self.preset = data,
+			TIMER_COUNT => self.count = data,
+			_ => (),
+		}
+		// The state machine holds in a cycle with a register write.
+		self.written = true;
+	}
+
+	fn is_read_only(&self, offset: u32) -> bool {
+		offset == TIMER_COUNT
+	}
+
+	fn irq(&self) -> bool {
+		self.ctrl & CTRL_IM != 0 && self.irq
+	}
+
+	fn tick(&mut self) {
+		if self.written {
+			self.written = false;
+			return;
+		}
+		match self.state {
+			TimerState::Idle => if self.ctrl & CTRL_ENABLE != 0 {
+				self.state = TimerState::Load;
+				self.irq = false;
+			},
+			TimerState::Load => {
+				self.count = self.preset;
+				self.state = TimerState::Count;
+			}
+			TimerState::Count => if self.ctrl & CTRL_ENABLE != 0 {
+				if self.count > 1 {
+					self.count -= 1;
+				} else {
+					self.count = 0;
+					self.state = TimerState::Int;
+					self.irq = true;
+				}
+			} else {
+				self.state = TimerState::Idle;
+			},
+			TimerState::Int => {
+				if self.ctrl & CTRL_MODE == 0 {
+					self.ctrl &= !CTRL_ENABLE;
+				} else {
+					self.irq = false;
+				}
+				self.state = TimerState::Idle;
+			}
+		}
+	}
+}